@@ -0,0 +1,286 @@
+use std::{path::Path, thread, time::{Duration, Instant}};
+
+use log::*;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{backup::{Backup, StepResult}, ffi, Connection};
+
+use crate::{connection_pool::PoolBuilder, error::*, migration_runner::{migrate, MigrationRunner}};
+
+/// Pages copied per step, the other connections can use the database in between.
+const PAGES_PER_STEP: i32 = 256;
+const BUSY_PAUSE: Duration = Duration::from_millis(50);
+/// How long a copy keeps waiting on a locked database before failing with [`StoreError::Busy`].
+pub const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupProgress {
+    pub remaining_pages: i32,
+    pub total_pages: i32,
+}
+
+/// Copies the database to and from a file with the SQLite online backup API, while the pool stays in use.
+pub struct BackupManager {
+    pool: Pool<SqliteConnectionManager>,
+    busy_timeout: Duration,
+}
+
+impl BackupManager {
+    pub fn new(pool: Pool<SqliteConnectionManager>) -> Self {
+        Self {
+            pool,
+            busy_timeout: DEFAULT_BUSY_TIMEOUT,
+        }
+    }
+
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = timeout;
+        self
+    }
+
+    /// Writes a consistent copy of the database to `path`, replacing whatever database is there.
+    pub fn backup_to<P, F>(&self, path: P, progress: F) -> Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnMut(BackupProgress),
+    {
+        let source = self.pool.get()?;
+        backup_to(&source, path.as_ref(), self.busy_timeout, progress)
+    }
+
+    /// Replaces the database with the one at `path`, then migrates it to the current schema.
+    ///
+    /// Fails without touching the database if `path` is not an encounter database this build can migrate.
+    pub fn restore_from<P, F>(&self, path: P, progress: F) -> Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnMut(BackupProgress),
+    {
+        let mut destination = self.pool.get()?;
+        restore_from(&mut destination, path.as_ref(), self.busy_timeout, progress)
+    }
+}
+
+pub(crate) fn backup_to<F: FnMut(BackupProgress)>(
+    source: &Connection,
+    path: &Path,
+    busy_timeout: Duration,
+    progress: F) -> Result<()>
+{
+    info!("backing up database to {}", path.display());
+
+    let mut destination = Connection::open(path)?;
+    // the copy loop does the waiting, a busy handler would block every step on top of it
+    destination.busy_timeout(Duration::ZERO)?;
+
+    copy(source, &mut destination, busy_timeout, progress)
+}
+
+pub(crate) fn restore_from<F: FnMut(BackupProgress)>(
+    destination: &mut Connection,
+    path: &Path,
+    busy_timeout: Duration,
+    progress: F) -> Result<()>
+{
+    info!("restoring database from {}", path.display());
+
+    let source_pool = PoolBuilder::file(path)
+        .read_only(true)
+        .max_size(1)
+        .build()?;
+
+    {
+        let source = source_pool.get()?;
+        ensure_encounter_database(&source, path)?;
+    }
+
+    MigrationRunner::new(source_pool.clone()).check()?;
+
+    {
+        let source = source_pool.get()?;
+        copy(&source, destination, busy_timeout, progress)?;
+    }
+
+    migrate(destination)?;
+
+    Ok(())
+}
+
+/// An empty file or an unrelated database would pass the schema check as version 0, so the source
+/// needs the migration ledger or, for databases from before it, the legacy encounter tables.
+fn ensure_encounter_database(source: &Connection, path: &Path) -> Result<()> {
+    let (has_ledger, has_legacy_tables): (bool, bool) = source.query_row(
+        "
+        SELECT
+            COUNT(CASE WHEN name = 'schema_migrations' THEN 1 END) > 0,
+            COUNT(CASE WHEN name IN ('encounter', 'encounter_preview') THEN 1 END) = 2
+        FROM sqlite_master
+        WHERE type = 'table'
+        ",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    if !has_ledger && !has_legacy_tables {
+        return Err(StoreError::InvalidExport(format!("{} is not an encounter database", path.display())));
+    }
+
+    Ok(())
+}
+
+/// Gives up with [`StoreError::Busy`] once the steps have been locked out for `busy_timeout` in a row.
+fn copy<F: FnMut(BackupProgress)>(
+    source: &Connection,
+    destination: &mut Connection,
+    busy_timeout: Duration,
+    mut progress: F) -> Result<()>
+{
+    let backup = Backup::new(source, destination)?;
+    let mut busy_since = None;
+
+    loop {
+        let result = backup.step(PAGES_PER_STEP)?;
+        let step_progress = backup.progress();
+
+        progress(BackupProgress {
+            remaining_pages: step_progress.remaining,
+            total_pages: step_progress.pagecount,
+        });
+
+        match result {
+            StepResult::Done => return Ok(()),
+            StepResult::More => busy_since = None,
+            _ => {
+                let since = *busy_since.get_or_insert_with(Instant::now);
+
+                if since.elapsed() >= busy_timeout {
+                    return Err(StoreError::Busy(rusqlite::Error::SqliteFailure(
+                        ffi::Error::new(ffi::SQLITE_BUSY),
+                        Some(format!("backup gave up after {:?}", busy_timeout)))));
+                }
+
+                thread::sleep(BUSY_PAUSE);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf, time::{SystemTime, UNIX_EPOCH}};
+
+    use crate::{connection_pool, encounter_service::{DefaultEncounterService, EncounterService}, migration_runner::latest_schema_version, repository::{test_utils::*, Repository, SqliteRepository}};
+
+    use super::*;
+
+    fn temp_db_path(name: &str) -> PathBuf {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+
+        env::temp_dir().join(format!("{}_{}.db", name, timestamp))
+    }
+
+    fn remove_db(path: &Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn should_restore_backup_into_other_database() {
+        let source = setup();
+        let id = TestEncounter::default().insert(&source);
+        let path = temp_db_path("backup");
+
+        let mut reports = vec![];
+        BackupManager::new(pool_of(&source)).backup_to(&path, |progress| reports.push(progress)).unwrap();
+        assert_eq!(reports.last().map(|progress| progress.remaining_pages), Some(0));
+
+        let pool = connection_pool::in_memory().unwrap();
+        BackupManager::new(pool.clone()).restore_from(&path, |_| {}).unwrap();
+
+        let restored = SqliteRepository::new(pool);
+        assert!(restored.load_encounter(id).is_ok());
+
+        remove_db(&path);
+    }
+
+    #[test]
+    fn should_refuse_to_restore_newer_schema() {
+        let path = temp_db_path("future");
+
+        {
+            let pool = connection_pool::get(&path).unwrap();
+            MigrationRunner::new(pool.clone()).run().unwrap();
+            pool.get().unwrap().execute(
+                "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, 'future', 0)",
+                [latest_schema_version() + 1]).unwrap();
+        }
+
+        let repository = setup();
+        let id = TestEncounter::default().insert(&repository);
+
+        let result = BackupManager::new(pool_of(&repository)).restore_from(&path, |_| {});
+        assert!(matches!(result, Err(StoreError::SchemaTooNew(_))));
+        assert!(repository.load_encounter(id).is_ok());
+
+        remove_db(&path);
+    }
+
+    #[test]
+    fn should_back_up_and_restore_through_service() {
+        let source = setup();
+        let id = TestEncounter::default().insert(&source);
+        let path = temp_db_path("service");
+
+        DefaultEncounterService::new(source).backup_to(&path, &mut |_| {}).unwrap();
+
+        let restored = setup();
+        DefaultEncounterService::new(same_database(&restored)).restore_from(&path, &mut |_| {}).unwrap();
+        assert!(restored.load_encounter(id).is_ok());
+
+        remove_db(&path);
+    }
+
+    #[test]
+    fn should_refuse_to_restore_non_encounter_database() {
+        let empty = temp_db_path("empty");
+        std::fs::write(&empty, b"").unwrap();
+
+        let unrelated = temp_db_path("unrelated");
+        Connection::open(&unrelated).unwrap().execute_batch("CREATE TABLE t (id INTEGER)").unwrap();
+
+        let repository = setup();
+        let id = TestEncounter::default().insert(&repository);
+        let manager = BackupManager::new(pool_of(&repository));
+
+        for path in [&empty, &unrelated] {
+            let result = manager.restore_from(path, |_| {});
+            assert!(matches!(result, Err(StoreError::InvalidExport(_))), "{:?}", result);
+        }
+
+        assert!(repository.load_encounter(id).is_ok());
+
+        remove_db(&empty);
+        remove_db(&unrelated);
+    }
+
+    #[test]
+    fn should_give_up_on_locked_destination() {
+        let repository = setup();
+        TestEncounter::default().insert(&repository);
+        let path = temp_db_path("locked");
+
+        let lock = Connection::open(&path).unwrap();
+        lock.execute_batch("CREATE TABLE t (id INTEGER); BEGIN EXCLUSIVE; INSERT INTO t VALUES (1);").unwrap();
+
+        let result = BackupManager::new(pool_of(&repository))
+            .busy_timeout(Duration::from_millis(200))
+            .backup_to(&path, |_| {});
+        assert!(matches!(result, Err(StoreError::Busy(_))));
+
+        drop(lock);
+        remove_db(&path);
+    }
+}
@@ -1,21 +1,252 @@
-use std::path::Path;
+use std::{path::{Path, PathBuf}, time::Duration};
 
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OpenFlags};
 
-pub fn get(path: &Path) -> Pool<SqliteConnectionManager> {
-    let manager = SqliteConnectionManager::file(&path);
-    let pool = r2d2::Pool::builder()
-        .build(manager).unwrap();
-    
-    pool
+use crate::error::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreLocation {
+    File(PathBuf),
+    /// Shared-cache in-memory database, each pool gets its own.
+    Memory,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoreConfig {
+    pub location: StoreLocation,
+    pub max_size: u32,
+    pub connection_timeout: Duration,
+    pub busy_timeout: Duration,
+    pub read_only: bool,
+    /// Applied after the defaults, so these can override them.
+    pub pragmas: Vec<(String, String)>,
+}
+
+impl StoreConfig {
+    pub fn file<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            location: StoreLocation::File(path.as_ref().to_path_buf()),
+            max_size: 10,
+            connection_timeout: Duration::from_secs(30),
+            busy_timeout: Duration::from_secs(5),
+            read_only: false,
+            pragmas: vec![],
+        }
+    }
+
+    pub fn in_memory() -> Self {
+        Self {
+            location: StoreLocation::Memory,
+            ..Self::file("")
+        }
+    }
+}
+
+pub struct PoolBuilder {
+    config: StoreConfig,
+}
+
+impl PoolBuilder {
+    pub fn new(config: StoreConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn file<P: AsRef<Path>>(path: P) -> Self {
+        Self::new(StoreConfig::file(path))
+    }
+
+    pub fn in_memory() -> Self {
+        Self::new(StoreConfig::in_memory())
+    }
+
+    pub fn max_size(mut self, max_size: u32) -> Self {
+        self.config.max_size = max_size;
+        self
+    }
+
+    pub fn connection_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connection_timeout = timeout;
+        self
+    }
+
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.config.busy_timeout = timeout;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.config.read_only = read_only;
+        self
+    }
+
+    pub fn pragma(mut self, name: &str, value: &str) -> Self {
+        self.config.pragmas.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn config(&self) -> &StoreConfig {
+        &self.config
+    }
+
+    /// Opens the pool, failing if the database can't be opened or initialized within the connection timeout.
+    pub fn build(self) -> Result<Pool<SqliteConnectionManager>> {
+        let config = self.config;
+
+        let manager = match &config.location {
+            StoreLocation::File(path) => {
+                let manager = SqliteConnectionManager::file(path);
+
+                if config.read_only {
+                    manager.with_flags(OpenFlags::SQLITE_OPEN_READ_ONLY
+                        | OpenFlags::SQLITE_OPEN_URI
+                        | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+                } else {
+                    manager
+                }
+            },
+            StoreLocation::Memory => SqliteConnectionManager::memory(),
+        };
+
+        let max_size = config.max_size;
+        let connection_timeout = config.connection_timeout;
+        let manager = manager.with_init(move |connection| init_connection(connection, &config));
+
+        let pool = r2d2::Pool::builder()
+            .max_size(max_size)
+            .connection_timeout(connection_timeout)
+            .build(manager)
+            .map_err(StoreError::Open)?;
+
+        Ok(pool)
+    }
+}
+
+pub fn get(path: &Path) -> Result<Pool<SqliteConnectionManager>> {
+    PoolBuilder::file(path).build()
+}
+
+pub fn in_memory() -> Result<Pool<SqliteConnectionManager>> {
+    PoolBuilder::in_memory().build()
+}
+
+/// Runs on every new pooled connection.
+///
+/// Foreign keys are off by default in SQLite, without them the `ON DELETE CASCADE` clauses do nothing.
+fn init_connection(connection: &mut Connection, config: &StoreConfig) -> Result<(), rusqlite::Error> {
+    connection.busy_timeout(config.busy_timeout)?;
+    connection.execute_batch(
+        "
+        PRAGMA foreign_keys = ON;
+        PRAGMA synchronous = NORMAL;
+        ")?;
+
+    // switching the journal mode is a write, auto_vacuum only takes effect on a new database,
+    // older ones are converted by `Repository::optimize_storage`
+    if !config.read_only {
+        connection.execute_batch(
+            "
+            PRAGMA auto_vacuum = INCREMENTAL;
+            PRAGMA journal_mode = WAL;
+            ")?;
+    }
+
+    for (name, value) in &config.pragmas {
+        connection.pragma_update(None, name, value)?;
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
-pub fn in_memory() -> Pool<SqliteConnectionManager> {
-    let manager = SqliteConnectionManager::memory();
-    let pool = r2d2::Pool::builder()
-        .build(manager).unwrap();
-    
-    pool
-}
\ No newline at end of file
+mod tests {
+    use std::{env, time::{SystemTime, UNIX_EPOCH}};
+
+    use crate::{migration_runner::MigrationRunner, repository::test_utils::*, repository::Repository};
+
+    use super::*;
+
+    #[test]
+    fn should_enable_foreign_keys() {
+        let pool = in_memory().unwrap();
+        let connection = pool.get().unwrap();
+
+        let foreign_keys: bool = connection.query_row("PRAGMA foreign_keys", [], |row| row.get(0)).unwrap();
+        assert!(foreign_keys);
+    }
+
+    #[test]
+    fn should_apply_custom_pragmas() {
+        let pool = PoolBuilder::in_memory()
+            .max_size(2)
+            .pragma("cache_size", "-4000")
+            .build()
+            .unwrap();
+        let connection = pool.get().unwrap();
+
+        let cache_size: i64 = connection.query_row("PRAGMA cache_size", [], |row| row.get(0)).unwrap();
+        assert_eq!(cache_size, -4000);
+        assert_eq!(pool.max_size(), 2);
+    }
+
+    #[test]
+    fn should_share_in_memory_database_between_connections() {
+        let pool = in_memory().unwrap();
+        let first = pool.get().unwrap();
+        let second = pool.get().unwrap();
+
+        first.execute_batch("CREATE TABLE test (id INTEGER)").unwrap();
+        second.execute("INSERT INTO test VALUES (1)", []).unwrap();
+    }
+
+    #[test]
+    fn should_fail_to_open_missing_database_read_only() {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let path = env::temp_dir().join(format!("missing_db_{}.db", timestamp));
+
+        let result = PoolBuilder::file(path)
+            .read_only(true)
+            .connection_timeout(Duration::from_millis(100))
+            .build();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn should_cascade_encounter_delete() {
+        let repository = setup();
+        let encounter_id = TestEncounter::default().insert(&repository);
+
+        let connection = repository.get_connection().unwrap();
+        connection.execute("INSERT INTO sync_logs (encounter_id, upstream_id) VALUES (?1, 'upstream')", [encounter_id]).unwrap();
+        connection.execute("DELETE FROM encounter WHERE id = ?1", [encounter_id]).unwrap();
+
+        let entities: i64 = connection
+            .query_row("SELECT COUNT(*) FROM entity WHERE encounter_id = ?1", [encounter_id], |row| row.get(0))
+            .unwrap();
+        let previews: i64 = connection
+            .query_row("SELECT COUNT(*) FROM encounter_preview WHERE id = ?1", [encounter_id], |row| row.get(0))
+            .unwrap();
+        let sync_logs: i64 = connection
+            .query_row("SELECT COUNT(*) FROM sync_logs WHERE encounter_id = ?1", [encounter_id], |row| row.get(0))
+            .unwrap();
+
+        assert_eq!(entities, 0);
+        assert_eq!(previews, 0);
+        assert_eq!(sync_logs, 0);
+    }
+
+    #[test]
+    fn should_reject_orphaned_entity() {
+        let pool = in_memory().unwrap();
+        MigrationRunner::new(pool.clone()).run().unwrap();
+        let connection = pool.get().unwrap();
+
+        let result = connection.execute("INSERT INTO entity (name, encounter_id) VALUES ('test', 404)", []);
+        assert!(result.is_err());
+    }
+}
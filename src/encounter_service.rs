@@ -1,15 +1,42 @@
-use std::cmp::{max, Reverse};
+use std::{cmp::max, path::Path};
 
-use crate::{models::*, repository::Repository, utils::to_entities_db};
-use anyhow::*;
+use crate::{backup::{self, BackupProgress, DEFAULT_BUSY_TIMEOUT}, error::*, models::*, repository::Repository, utils::{to_entities_db, to_entity_db, to_preview_players}};
+use chrono::{Duration, Utc};
+use hashbrown::HashSet;
 use lost_metrics_core::models::EncounterMisc;
 use lost_metrics_misc::{compress_json, create_stagger_stats, generate_intervals};
+use rusqlite::TransactionBehavior;
 use serde_json::json;
 
 pub const DB_VERSION: i32 = 5;
 
 pub trait EncounterService : Send + Sync + 'static {
-    fn create(&self, payload: CreateEncounter) -> Result<i64>;
+    /// Saves the encounter and reports whether the local player set a new personal best with it.
+    fn create(&self, payload: CreateEncounter) -> Result<CreatedEncounter>;
+    fn delete_encounter(&self, id: i64) -> Result<()>;
+    fn delete_encounters(&self, ids: &[i64]) -> Result<usize>;
+    fn set_favorite(&self, id: i64, favorite: bool) -> Result<()>;
+    /// Updates all or none, failing with [`StoreError::NotFound`] on the first unknown id.
+    fn set_favorite_many(&self, ids: &[i64], favorite: bool) -> Result<()>;
+    fn set_cleared(&self, id: i64, cleared: bool) -> Result<()>;
+    /// Updates all or none, failing with [`StoreError::NotFound`] on the first unknown id.
+    fn set_cleared_many(&self, ids: &[i64], cleared: bool) -> Result<()>;
+    /// Serializes the encounter into an [`EncounterExport`] envelope.
+    fn export_encounter(&self, id: i64) -> Result<Vec<u8>>;
+    /// Saves an encounter written by [`EncounterService::export_encounter`] under a new id, which is returned.
+    fn import_encounter(&self, export: &[u8]) -> Result<i64>;
+    /// Deletes the encounters the policy doesn't keep, then compacts the database.
+    fn prune(&self, policy: &RetentionPolicy) -> Result<RetentionReport>;
+    fn storage_stats(&self) -> Result<StorageStats>;
+    fn check_integrity(&self) -> Result<IntegrityReport>;
+    /// Cleans up what [`EncounterService::check_integrity`] reports, in a single transaction.
+    fn repair(&self) -> Result<RepairReport>;
+    /// Recomputes the previews of the given encounters, or of all of them, from the stored encounter and entities.
+    fn rebuild_previews(&self, ids: Option<&[i64]>) -> Result<usize>;
+    /// Writes a consistent copy of the database to `path` while it stays in use.
+    fn backup_to(&self, path: &Path, progress: &mut dyn FnMut(BackupProgress)) -> Result<()>;
+    /// Replaces the database with the one at `path` and migrates it, see [`backup::BackupManager::restore_from`].
+    fn restore_from(&self, path: &Path, progress: &mut dyn FnMut(BackupProgress)) -> Result<()>;
 }
 
 pub struct DefaultEncounterService<R: Repository> {
@@ -17,7 +44,7 @@ pub struct DefaultEncounterService<R: Repository> {
 }
 
 impl<R: Repository> EncounterService for DefaultEncounterService<R> {
-    fn create(&self, payload: CreateEncounter) -> Result<i64> {
+    fn create(&self, payload: CreateEncounter) -> Result<CreatedEncounter> {
 
         let mut encounter = payload.encounter;
         let raid_clear = payload.raid_clear;
@@ -77,22 +104,24 @@ impl<R: Repository> EncounterService for DefaultEncounterService<R> {
         let compressed_debuffs = compress_json(&encounter_damage_stats.debuffs);
         let compressed_shields = compress_json(&encounter_damage_stats.applied_shield_buffs);
         
-        let mut players = encounter
+        let players = encounter
             .entities
             .values()
             .filter(|entity| entity.is_active_player(local_player))
             .collect::<Vec<_>>();
-        let local_player_dps = players
+        let local_entity = players
             .iter()
             .find(|e| &e.name == local_player)
+            .copied();
+        let local_player_dps = local_entity
             .map(|e| e.damage_stats.dps)
             .unwrap_or_default();
-        players.sort_unstable_by_key(|e| Reverse(e.damage_stats.damage_dealt));
-        let preview_players = players
-            .into_iter()
-            .map(|e| format!("{}:{}", e.class_id, e.name))
-            .collect::<Vec<_>>()
-            .join(",");
+        let best_of = match local_entity {
+            Some(entity) if entity.character_id != 0 => Some(PersonalBestOf::Character(entity.character_id)),
+            Some(entity) => Some(PersonalBestOf::Class(entity.class_id)),
+            None => None,
+        };
+        let preview_players = to_preview_players(players);
 
         let filtered: Vec<_> = encounter.entities
             .iter_mut()
@@ -137,7 +166,7 @@ impl<R: Repository> EncounterService for DefaultEncounterService<R> {
             fight_start: encounter.fight_start,
             current_boss_name: &encounter.current_boss_name,
             duration: encounter.duration,
-            preview_players: &preview_players,
+            players: preview_players,
             raid_difficulty: &raid_difficulty,
             local_player: &local_player,
             local_player_dps,
@@ -145,10 +174,250 @@ impl<R: Repository> EncounterService for DefaultEncounterService<R> {
             boss_only_damage: encounter.boss_only_damage
         };
 
-        let encounter_id = self.insert_encounter_and_entities(encounter_db, encounter_preview, entities)?;
+        let duration = encounter.duration;
+        let cleared = raid_clear.unwrap_or_default();
+
+        let (encounter_id, previous_best) = self.insert_encounter_and_entities(encounter_db, encounter_preview, entities, best_of)?;
+
+        let (new_dps_best, new_clear_best) = match previous_best {
+            Some(best) => (
+                local_player_dps > best.dps.map_or(0, |entry| entry.value),
+                cleared && best.clear_duration.is_none_or(|entry| duration < entry.value),
+            ),
+            None => (false, false),
+        };
+
+        Ok(CreatedEncounter {
+            id: encounter_id,
+            new_dps_best,
+            new_clear_best,
+        })
+    }
+
+    fn delete_encounter(&self, id: i64) -> Result<()> {
+        let deleted = self.delete_encounters(&[id])?;
+
+        if deleted == 0 {
+            return Err(StoreError::NotFound(id));
+        }
+
+        Ok(())
+    }
+
+    fn delete_encounters(&self, ids: &[i64]) -> Result<usize> {
+        let mut connection = self.repository.get_connection()?;
+        let transaction = connection.transaction()?;
+
+        let deleted = self.repository.delete_encounters(&transaction, ids)?;
+
+        transaction.commit()?;
+
+        Ok(deleted)
+    }
+
+    fn set_favorite(&self, id: i64, favorite: bool) -> Result<()> {
+        let connection = self.repository.get_connection()?;
+        self.repository.set_favorite(&connection, id, favorite)
+    }
+
+    fn set_favorite_many(&self, ids: &[i64], favorite: bool) -> Result<()> {
+        let mut connection = self.repository.get_connection()?;
+        let transaction = connection.transaction()?;
+
+        self.repository.set_favorite_many(&transaction, ids, favorite)?;
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    fn set_cleared(&self, id: i64, cleared: bool) -> Result<()> {
+        let connection = self.repository.get_connection()?;
+        self.repository.set_cleared(&connection, id, cleared)
+    }
+
+    fn set_cleared_many(&self, ids: &[i64], cleared: bool) -> Result<()> {
+        let mut connection = self.repository.get_connection()?;
+        let transaction = connection.transaction()?;
+
+        self.repository.set_cleared_many(&transaction, ids, cleared)?;
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    fn export_encounter(&self, id: i64) -> Result<Vec<u8>> {
+        let encounter = self.repository.load_encounter(id)?;
+
+        let export = EncounterExport {
+            format: EncounterExport::FORMAT.to_string(),
+            version: EncounterExport::VERSION,
+            db_version: DB_VERSION,
+            exported_at: Utc::now().timestamp_millis(),
+            encounter,
+        };
+
+        Ok(serde_json::to_vec(&export)?)
+    }
+
+    fn import_encounter(&self, export: &[u8]) -> Result<i64> {
+        let export: EncounterExport = serde_json::from_slice(export)
+            .map_err(|err| StoreError::InvalidExport(err.to_string()))?;
+
+        if export.format != EncounterExport::FORMAT {
+            return Err(StoreError::InvalidExport(format!("unknown format '{}'", export.format)));
+        }
+
+        if export.version > EncounterExport::VERSION || export.db_version > DB_VERSION {
+            return Err(StoreError::InvalidExport(format!(
+                "export version {} (db version {}) is newer than supported version {} (db version {})",
+                export.version,
+                export.db_version,
+                EncounterExport::VERSION,
+                DB_VERSION)));
+        }
+
+        let encounter = export.encounter;
+        let local_player = &encounter.local_player;
+        let encounter_damage_stats = &encounter.encounter_damage_stats;
+
+        let encounter_db = EncounterDb {
+            last_combat_packet: encounter.last_combat_packet,
+            total_damage_dealt: encounter_damage_stats.total_damage_dealt,
+            top_damage_dealt: encounter_damage_stats.top_damage_dealt,
+            total_damage_taken: encounter_damage_stats.total_damage_taken,
+            top_damage_taken: encounter_damage_stats.top_damage_taken,
+            dps: encounter_damage_stats.dps,
+            compressed_buffs: compress_json(&encounter_damage_stats.buffs),
+            compressed_debuffs: compress_json(&encounter_damage_stats.debuffs),
+            total_shielding: encounter_damage_stats.total_shielding,
+            total_effective_shielding: encounter_damage_stats.total_effective_shielding,
+            compressed_shields: compress_json(&encounter_damage_stats.applied_shield_buffs),
+            misc_json: json!(encounter_damage_stats.misc),
+            db_version: DB_VERSION,
+            compressed_boss_hp: compress_json(&encounter_damage_stats.boss_hp_log),
+            stagger_stats_json: json!(encounter_damage_stats.stagger_stats),
+        };
+
+        let players = encounter
+            .entities
+            .values()
+            .filter(|entity| entity.is_active_player(local_player))
+            .collect::<Vec<_>>();
+        let local_player_dps = players
+            .iter()
+            .find(|e| &e.name == local_player)
+            .map(|e| e.damage_stats.dps)
+            .unwrap_or_default();
+
+        let encounter_preview = EncounterPreviewDb {
+            fight_start: encounter.fight_start,
+            current_boss_name: &encounter.current_boss_name,
+            duration: encounter.duration,
+            players: to_preview_players(players),
+            raid_difficulty: encounter.difficulty.as_deref().unwrap_or_default(),
+            local_player,
+            local_player_dps,
+            raid_clear: encounter.cleared.then_some(true),
+            boss_only_damage: encounter.boss_only_damage
+        };
+
+        let entities: Vec<_> = encounter.entities.values().map(to_entity_db).collect();
+
+        let repository = &self.repository;
+        let mut connection = repository.get_connection()?;
+        let transaction = connection.transaction()?;
+
+        let encounter_id = repository.insert_encounter(&transaction, encounter_db)?;
+        repository.insert_entities(&transaction, encounter_id, &entities)?;
+        repository.insert_encounter_preview(&transaction, encounter_id, encounter_preview)?;
+
+        if encounter.favorite {
+            repository.set_favorite(&transaction, encounter_id, true)?;
+        }
+
+        transaction.commit()?;
 
         Ok(encounter_id)
     }
+
+    fn prune(&self, policy: &RetentionPolicy) -> Result<RetentionReport> {
+        let fight_start_before = policy.max_age_days
+            .map(|days| (Utc::now() - Duration::days(days as i64)).timestamp_millis());
+
+        let mut encounter_ids = vec![];
+        let mut pending = if fight_start_before.is_some() || policy.max_per_boss.is_some() {
+            self.repository.load_prunable_encounters(fight_start_before, policy.max_per_boss)?
+        } else {
+            vec![]
+        };
+
+        loop {
+            if let Some(max_size_bytes) = policy.max_size_bytes {
+                self.select_over_budget(max_size_bytes, &mut pending)?;
+            }
+
+            if policy.dry_run || pending.is_empty() {
+                encounter_ids.append(&mut pending);
+                break;
+            }
+
+            self.delete_encounters(&pending)?;
+            self.repository.optimize_storage()?;
+            encounter_ids.append(&mut pending);
+
+            // the encounter sizes are estimates, so measure again and keep evicting while over budget
+            if policy.max_size_bytes.is_none() {
+                break;
+            }
+        }
+
+        Ok(RetentionReport {
+            encounter_ids,
+            dry_run: policy.dry_run,
+        })
+    }
+
+    fn storage_stats(&self) -> Result<StorageStats> {
+        self.repository.load_storage_stats()
+    }
+
+    fn check_integrity(&self) -> Result<IntegrityReport> {
+        self.repository.check_integrity()
+    }
+
+    fn repair(&self) -> Result<RepairReport> {
+        let mut connection = self.repository.get_connection()?;
+        let transaction = connection.transaction()?;
+
+        let report = self.repository.repair(&transaction)?;
+
+        transaction.commit()?;
+
+        Ok(report)
+    }
+
+    fn rebuild_previews(&self, ids: Option<&[i64]>) -> Result<usize> {
+        let mut connection = self.repository.get_connection()?;
+        let transaction = connection.transaction()?;
+
+        let rebuilt = self.repository.rebuild_previews(&transaction, ids)?;
+
+        transaction.commit()?;
+
+        Ok(rebuilt)
+    }
+
+    fn backup_to(&self, path: &Path, progress: &mut dyn FnMut(BackupProgress)) -> Result<()> {
+        let connection = self.repository.get_connection()?;
+        backup::backup_to(&connection, path, DEFAULT_BUSY_TIMEOUT, progress)
+    }
+
+    fn restore_from(&self, path: &Path, progress: &mut dyn FnMut(BackupProgress)) -> Result<()> {
+        let mut connection = self.repository.get_connection()?;
+        backup::restore_from(&mut connection, path, DEFAULT_BUSY_TIMEOUT, progress)
+    }
 }
 
 impl<R: Repository> DefaultEncounterService<R> {
@@ -158,14 +427,59 @@ impl<R: Repository> DefaultEncounterService<R> {
         }
     }
 
+    /// Adds the oldest evictable encounters to `encounter_ids` until their estimated size covers
+    /// how far the database is over the budget.
+    fn select_over_budget(&self, max_size_bytes: u64, encounter_ids: &mut Vec<i64>) -> Result<()> {
+        let used_bytes = self.repository.load_used_bytes()?;
+        let mut excess = used_bytes - max_size_bytes as i64;
+
+        if excess <= 0 {
+            return Ok(());
+        }
+
+        let costs = self.repository.load_evictable_encounters()?;
+        let mut selected: HashSet<i64> = encounter_ids.iter().copied().collect();
+
+        // whatever the age and per boss limits already prune counts towards the budget
+        excess -= costs
+            .iter()
+            .filter(|cost| selected.contains(&cost.encounter_id))
+            .map(|cost| cost.bytes)
+            .sum::<i64>();
+
+        for cost in costs {
+            if excess <= 0 {
+                break;
+            }
+
+            if selected.insert(cost.encounter_id) {
+                encounter_ids.push(cost.encounter_id);
+                excess -= cost.bytes;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Also returns the personal best from before the insert, looked up under the same write lock
+    /// so two saves can't both beat the same best.
     fn insert_encounter_and_entities<'a>(&self,
         encounter: EncounterDb,
         encounter_preview: EncounterPreviewDb<'a>,
-        entities: Vec<EntityDb<'a>>) -> Result<i64>
+        entities: Vec<EntityDb<'a>>,
+        best_of: Option<PersonalBestOf>) -> Result<(i64, Option<PersonalBest>)>
     {
         let repository = &self.repository;
         let mut connection = self.repository.get_connection()?;
-        let transaction = connection.transaction()?;
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let previous_best = best_of
+            .map(|of| repository.load_personal_best(
+                &transaction,
+                encounter_preview.current_boss_name,
+                encounter_preview.raid_difficulty,
+                of))
+            .transpose()?;
 
         let encounter_id = repository.insert_encounter(&transaction, encounter)?;
         repository.insert_entities(&transaction, encounter_id, &entities)?;
@@ -173,7 +487,7 @@ impl<R: Repository> DefaultEncounterService<R> {
 
         transaction.commit()?;
 
-        Ok(encounter_id)
+        Ok((encounter_id, previous_best))
     }
 }
 
@@ -183,13 +497,13 @@ mod tests {
     use hashbrown::{HashMap, HashSet};
     use lost_metrics_core::models::{DamageStats, Encounter, EncounterDamageStats, EncounterEntity, EntityType, MostDamageTakenEntity, SkillStats};
 
-    use crate::{connection_pool, migration_runner::{self, MigrationRunner}, repository::SqliteRepository};
+    use crate::{connection_pool, migration_runner::{self, MigrationRunner}, repository::{test_utils::*, SqliteRepository}};
 
     use super::*;
 
     #[test]
     fn should_create_new_encounter() {
-        let pool = connection_pool::in_memory();
+        let pool = connection_pool::in_memory().unwrap();
         let repository = SqliteRepository::new(pool.clone());
         let migration_runner = MigrationRunner::new(pool);
         let service = DefaultEncounterService::new(repository);
@@ -310,4 +624,299 @@ mod tests {
 
         service.create(payload).unwrap();
     }
+
+    fn attempt(duration_seconds: i64, dps: i64, raid_clear: bool) -> CreateEncounter {
+        let fight_start = 1_000_000;
+        let local_player = EncounterEntity {
+            id: 1,
+            character_id: 1,
+            npc_id: 0,
+            name: "test".into(),
+            entity_type: EntityType::Player,
+            class_id: 204,
+            class: "Bard".into(),
+            gear_score: 1700.0,
+            current_hp: 0,
+            max_hp: 1000,
+            current_shield: 0,
+            is_dead: false,
+            skills: HashMap::new(),
+            damage_stats: DamageStats {
+                damage_dealt: dps * duration_seconds,
+                dps,
+                ..Default::default()
+            },
+            skill_stats: SkillStats::default(),
+            engraving_data: None,
+            gear_hash: None,
+            ark_passive_active: None,
+            ark_passive_data: None,
+            spec: None,
+        };
+
+        let encounter = Encounter {
+            last_combat_packet: fight_start + duration_seconds * 1000,
+            fight_start,
+            local_player: "test".into(),
+            entities: HashMap::from([("test".to_string(), local_player)]),
+            current_boss_name: "Narok the Butcher".into(),
+            current_boss: None,
+            encounter_damage_stats: EncounterDamageStats {
+                total_damage_dealt: dps * duration_seconds,
+                top_damage_dealt: dps * duration_seconds,
+                total_damage_taken: 0,
+                top_damage_taken: 0,
+                dps,
+                most_damage_taken_entity: MostDamageTakenEntity {
+                    name: "test".into(),
+                    damage_taken: 0,
+                },
+                buffs: HashMap::new(),
+                debuffs: HashMap::new(),
+                total_shielding: 0,
+                total_effective_shielding: 0,
+                applied_shield_buffs: HashMap::new(),
+                unknown_buffs: HashSet::new(),
+                max_stagger: 0,
+                stagger_start: 0,
+                misc: None,
+                boss_hp_log: HashMap::new(),
+                stagger_stats: None,
+            },
+            duration: duration_seconds * 1000,
+            difficulty: Some("Hard".into()),
+            favorite: false,
+            cleared: raid_clear,
+            boss_only_damage: false,
+            sync: None,
+        };
+
+        CreateEncounter {
+            encounter,
+            prev_stagger: 0,
+            damage_log: HashMap::new(),
+            identity_log: HashMap::new(),
+            cast_log: HashMap::new(),
+            boss_hp_log: HashMap::new(),
+            stagger_log: vec![],
+            stagger_intervals: vec![],
+            raid_clear,
+            party_info: vec![],
+            raid_difficulty: "Hard".into(),
+            region: None,
+            player_info: None,
+            version: "0.0.1".into(),
+            ntp_fight_start: 0,
+            rdps_valid: true,
+            manual: false,
+            skill_cast_log: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn should_report_personal_bests() {
+        let service = DefaultEncounterService::new(setup());
+
+        // nothing to beat yet, the first attempt sets the bests
+        let first = service.create(attempt(600, 100, true)).unwrap();
+        assert!(first.new_dps_best);
+        assert!(first.new_clear_best);
+
+        let best = service.create(attempt(500, 200, true)).unwrap();
+        assert!(best.new_dps_best);
+        assert!(best.new_clear_best);
+
+        let slower = service.create(attempt(550, 150, true)).unwrap();
+        assert!(!slower.new_dps_best);
+        assert!(!slower.new_clear_best);
+
+        let faster_wipe = service.create(attempt(300, 300, false)).unwrap();
+        assert!(faster_wipe.new_dps_best);
+        assert!(!faster_wipe.new_clear_best);
+    }
+
+    #[test]
+    fn should_roll_back_bulk_update_on_unknown_id() {
+        let repository = setup();
+        let id = TestEncounter::default().insert(&repository);
+        let connection = repository.get_connection().unwrap();
+        let service = DefaultEncounterService::new(repository);
+
+        let result = service.set_favorite_many(&[id, 404], true);
+        assert!(matches!(result, Err(StoreError::NotFound(404))));
+
+        let favorite: bool = connection
+            .query_row("SELECT favorite FROM encounter_preview WHERE id = ?1", [id], |row| row.get(0))
+            .unwrap();
+        assert!(!favorite);
+
+        service.set_favorite(id, true).unwrap();
+        assert!(matches!(service.set_cleared(404, true), Err(StoreError::NotFound(404))));
+    }
+
+    #[test]
+    fn should_import_exported_encounter_into_other_database() {
+        let source = setup();
+        let id = TestEncounter {
+            players: vec![
+                TestPlayer { name: "test", class_id: 204, character_id: 1, gear_score: 1700.0, dps: 100 },
+                TestPlayer { name: "other", class_id: 102, character_id: 2, gear_score: 1710.0, dps: 300 },
+            ],
+            region: Some("EUC"),
+            ..Default::default()
+        }.insert(&source);
+        let source = DefaultEncounterService::new(source);
+        source.set_favorite(id, true).unwrap();
+
+        let export = source.export_encounter(id).unwrap();
+
+        let target = setup();
+        TestEncounter::default().insert(&target);
+        let target = DefaultEncounterService::new(target);
+        let imported_id = target.import_encounter(&export).unwrap();
+        assert_ne!(imported_id, id);
+
+        let original = source.repository.load_encounter(id).unwrap();
+        let imported = target.repository.load_encounter(imported_id).unwrap();
+
+        assert_eq!(json!(imported), json!(original));
+        assert!(imported.favorite);
+        assert!(imported.cleared);
+
+        let page = target.repository.load_encounters_preview_page(None, 10, "".into(), EncounterFilter::default(), false).unwrap();
+        let preview = page.encounters.iter().find(|item| item.preview.id as i64 == imported_id).unwrap();
+        assert_eq!(preview.preview.names, vec!["other".to_string(), "test".to_string()]);
+        assert_eq!(preview.preview.my_dps, 100);
+    }
+
+    #[test]
+    fn should_prune_old_encounters_unless_dry_run() {
+        let repository = setup();
+        let old = TestEncounter { fight_start: 1_000, cleared: false, ..Default::default() }.insert(&repository);
+        let recent = TestEncounter {
+            fight_start: Utc::now().timestamp_millis(),
+            cleared: false,
+            ..Default::default()
+        }.insert(&repository);
+        let service = DefaultEncounterService::new(repository);
+
+        let policy = RetentionPolicy {
+            max_age_days: Some(30),
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = service.prune(&policy).unwrap();
+        assert_eq!(report.encounter_ids, vec![old]);
+        assert!(service.repository.load_encounter(old).is_ok());
+
+        let report = service.prune(&RetentionPolicy { dry_run: false, ..policy }).unwrap();
+        assert_eq!(report.encounter_ids, vec![old]);
+        assert!(matches!(service.repository.load_encounter(old), Err(StoreError::NotFound(_))));
+        assert!(service.repository.load_encounter(recent).is_ok());
+    }
+
+    #[test]
+    fn should_release_free_pages_after_prune() {
+        let repository = setup();
+        let connection = repository.get_connection().unwrap();
+
+        for _ in 0..20 {
+            let id = TestEncounter { fight_start: 1_000, cleared: false, ..Default::default() }.insert(&repository);
+            repository.set_note(&connection, id, &"note ".repeat(4_000)).unwrap();
+        }
+
+        let page_count = |connection: &rusqlite::Connection| -> i64 {
+            connection.query_row("PRAGMA page_count", [], |row| row.get(0)).unwrap()
+        };
+        let before = page_count(&connection);
+        let service = DefaultEncounterService::new(same_database(&repository));
+
+        let policy = RetentionPolicy {
+            max_age_days: Some(30),
+            ..Default::default()
+        };
+        assert_eq!(service.prune(&policy).unwrap().encounter_ids.len(), 20);
+
+        let free_pages: i64 = connection.query_row("PRAGMA freelist_count", [], |row| row.get(0)).unwrap();
+        assert_eq!(free_pages, 0);
+        assert!(page_count(&connection) < before);
+    }
+
+    #[test]
+    fn should_evict_oldest_encounters_over_size_budget() {
+        let repository = setup();
+        let oldest = TestEncounter { fight_start: 1_000, cleared: false, ..Default::default() }.insert(&repository);
+        let older = TestEncounter { fight_start: 2_000, cleared: false, ..Default::default() }.insert(&repository);
+        TestEncounter { fight_start: 3_000, cleared: false, ..Default::default() }.insert(&repository);
+        TestEncounter { fight_start: 500, cleared: true, ..Default::default() }.insert(&repository);
+        let service = DefaultEncounterService::new(repository);
+
+        let used_bytes = service.storage_stats().unwrap().used_bytes;
+        let oldest_bytes = service.repository.load_evictable_encounters().unwrap()[0].bytes;
+
+        let policy = RetentionPolicy {
+            max_size_bytes: Some((used_bytes - oldest_bytes - 1) as u64),
+            dry_run: true,
+            ..Default::default()
+        };
+        assert_eq!(service.prune(&policy).unwrap().encounter_ids, vec![oldest, older]);
+
+        let policy = RetentionPolicy {
+            max_size_bytes: Some(used_bytes as u64),
+            ..policy
+        };
+        assert!(service.prune(&policy).unwrap().encounter_ids.is_empty());
+    }
+
+    #[test]
+    fn should_measure_again_until_under_size_budget() {
+        let repository = setup();
+
+        // interleaved so the oldest rows share their pages with newer ones
+        let mut oldest_first: Vec<_> = (0..200)
+            .map(|index| {
+                let fight_start = if index % 2 == 0 { index } else { 10_000 + index };
+                let id = TestEncounter { fight_start, cleared: false, ..Default::default() }.insert(&repository);
+                (fight_start, id)
+            })
+            .collect();
+        oldest_first.sort();
+        let oldest_first: Vec<_> = oldest_first.into_iter().map(|(_, id)| id).collect();
+
+        let service = DefaultEncounterService::new(same_database(&repository));
+        let used_bytes = service.storage_stats().unwrap().used_bytes;
+        let max_size_bytes = (used_bytes - 16_384) as u64;
+
+        let policy = RetentionPolicy {
+            max_size_bytes: Some(max_size_bytes),
+            dry_run: true,
+            ..Default::default()
+        };
+        let estimated = service.prune(&policy).unwrap().encounter_ids.len();
+
+        let policy = RetentionPolicy {
+            dry_run: false,
+            ..policy
+        };
+        let pruned = service.prune(&policy).unwrap().encounter_ids;
+
+        // deleting rows from shared pages frees less than their estimated size
+        assert!(pruned.len() > estimated);
+        assert_eq!(pruned, oldest_first[..pruned.len()]);
+        assert!(service.storage_stats().unwrap().used_bytes <= max_size_bytes as i64);
+    }
+
+    #[test]
+    fn should_reject_export_from_newer_build() {
+        let service = DefaultEncounterService::new(setup());
+        let id = TestEncounter::default().insert(&service.repository);
+
+        let mut export: serde_json::Value = serde_json::from_slice(&service.export_encounter(id).unwrap()).unwrap();
+        export["dbVersion"] = json!(DB_VERSION + 1);
+        let result = service.import_encounter(&serde_json::to_vec(&export).unwrap());
+        assert!(matches!(result, Err(StoreError::InvalidExport(_))));
+
+        let result = service.import_encounter(b"{}");
+        assert!(matches!(result, Err(StoreError::InvalidExport(_))));
+    }
 }
\ No newline at end of file
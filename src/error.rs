@@ -0,0 +1,91 @@
+use std::fmt;
+
+use rusqlite::ErrorCode;
+
+use crate::migration_runner::SchemaTooNew;
+
+pub type Result<T, E = StoreError> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// The pool could not be opened, usually an unreadable or locked database file.
+    Open(r2d2::Error),
+    /// No connection became available within the connection timeout.
+    PoolExhausted(r2d2::Error),
+    /// The database stayed locked past the busy timeout.
+    Busy(rusqlite::Error),
+    NotFound(i64),
+    InvalidFilter(String),
+    SchemaTooNew(SchemaTooNew),
+    /// An import that isn't an encounter export or one from a newer build, or a restore from a file that isn't an encounter database.
+    InvalidExport(String),
+    /// Stored data that can't be read back, either at the SQLite level or in a compressed json column.
+    Corrupt(String),
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Open(err) => write!(f, "could not open database: {}", err),
+            StoreError::PoolExhausted(err) => write!(f, "no database connection available: {}", err),
+            StoreError::Busy(err) => write!(f, "database is busy: {}", err),
+            StoreError::NotFound(id) => write!(f, "encounter {} not found", id),
+            StoreError::InvalidFilter(message) => write!(f, "invalid filter: {}", message),
+            StoreError::SchemaTooNew(err) => err.fmt(f),
+            StoreError::InvalidExport(message) => write!(f, "invalid export: {}", message),
+            StoreError::Corrupt(message) => write!(f, "database is corrupt: {}", message),
+            StoreError::Sqlite(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StoreError::Open(err) | StoreError::PoolExhausted(err) => Some(err),
+            StoreError::Busy(err) | StoreError::Sqlite(err) => Some(err),
+            StoreError::SchemaTooNew(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        match err.sqlite_error_code() {
+            Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => return StoreError::Busy(err),
+            Some(ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase) => return StoreError::Corrupt(err.to_string()),
+            _ => {}
+        }
+
+        match err {
+            rusqlite::Error::FromSqlConversionFailure(..) => StoreError::Corrupt(err.to_string()),
+            err => StoreError::Sqlite(err),
+        }
+    }
+}
+
+impl From<r2d2::Error> for StoreError {
+    fn from(err: r2d2::Error) -> Self {
+        StoreError::PoolExhausted(err)
+    }
+}
+
+impl From<SchemaTooNew> for StoreError {
+    fn from(err: SchemaTooNew) -> Self {
+        StoreError::SchemaTooNew(err)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(err: serde_json::Error) -> Self {
+        StoreError::Corrupt(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(err: std::io::Error) -> Self {
+        StoreError::Corrupt(err.to_string())
+    }
+}
@@ -1,9 +1,12 @@
 pub mod connection_pool;
+pub mod error;
 pub mod migration_runner;
 pub mod repository;
 pub mod models;
 pub mod utils;
 pub mod encounter_service;
+pub mod sync;
+pub mod backup;
 
 // pub use connection_pool;
 // pub use migration_runner;
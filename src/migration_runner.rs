@@ -1,56 +1,218 @@
+use std::fmt;
+
+use chrono::Utc;
 use log::*;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::Transaction;
-use anyhow::*;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use serde_json::json;
+
+use crate::{encounter_service::DB_VERSION, error::*, utils::load_preview_players};
 
 pub struct MigrationRunner {
     pool: Pool<SqliteConnectionManager> 
 }
 
+type MigrationFn = fn(&Transaction) -> Result<(), rusqlite::Error>;
+
+struct Migration {
+    version: u32,
+    name: &'static str,
+    up: MigrationFn,
+}
+
+/// Ordered list of schema changes, append only.
+/// Each one runs exactly once and is recorded in `schema_migrations`.
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "legacy_encounter", up: migration_legacy_encounter },
+    Migration { version: 2, name: "legacy_entity", up: migration_legacy_entity },
+    Migration { version: 3, name: "full_text_search", up: migration_full_text_search },
+    Migration { version: 4, name: "sync", up: migration_sync },
+    Migration { version: 5, name: "specs", up: migration_specs },
+    Migration { version: 6, name: "participant_indexes", up: migration_participant_indexes },
+    Migration { version: 7, name: "preview_player_data", up: migration_preview_player_data },
+    Migration { version: 8, name: "tags_and_notes", up: migration_tags_and_notes },
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedMigration {
+    pub version: u32,
+    pub name: &'static str,
+}
+
+/// The database was written by a newer build, anything written now could be misread by it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaTooNew {
+    pub schema_version: u32,
+    pub supported_schema_version: u32,
+    pub encounter_version: i32,
+    pub supported_encounter_version: i32,
+}
+
+impl fmt::Display for SchemaTooNew {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "database schema {} (encounter version {}) is newer than supported schema {} (encounter version {})",
+            self.schema_version,
+            self.encounter_version,
+            self.supported_schema_version,
+            self.supported_encounter_version)
+    }
+}
+
+impl std::error::Error for SchemaTooNew {}
+
 impl MigrationRunner {
     pub fn new(pool: Pool<SqliteConnectionManager>) -> Self {
         Self { pool }
     }
 
-    pub fn run(&self) -> Result<()> {
-        info!("setting up database");
-        let mut connection= self.pool.get()?;
+    /// Applies pending migrations in order and returns the ones that ran.
+    pub fn run(&self) -> Result<Vec<AppliedMigration>> {
+        let mut connection = self.pool.get()?;
+        migrate(&mut connection)
+    }
+
+    /// Checks that this build can migrate the database, without changing it. Returns its schema version.
+    pub fn check(&self) -> Result<u32> {
+        let connection = self.pool.get()?;
+
+        let has_ledger = connection
+            .query_row("SELECT 1 FROM sqlite_master WHERE type='table' AND name='schema_migrations'", [], |_| Ok(()))
+            .optional()?
+            .is_some();
+
+        let current_version = if has_ledger {
+            get_schema_version(&connection)?
+        } else {
+            0
+        };
+        ensure_supported(&connection, current_version)?;
+
+        Ok(current_version)
+    }
+}
+
+/// [`MigrationRunner::run`] on a connection the caller already holds.
+pub(crate) fn migrate(connection: &mut Connection) -> Result<Vec<AppliedMigration>> {
+    info!("setting up database");
+
+    connection.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        );
+        ")?;
+
+    let current_version = get_schema_version(connection)?;
+    ensure_supported(connection, current_version)?;
+
+    let transaction = connection.transaction()?;
+    adopt_legacy_schema(&transaction)?;
+    transaction.commit()?;
+
+    let current_version = get_schema_version(connection)?;
+    let mut applied = vec![];
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current_version) {
+        info!("applying migration {} {}", migration.version, migration.name);
+
         let transaction = connection.transaction()?;
-    
-        // FIXME: replace me with idempotent migrations
-    
-        let mut statement = transaction.prepare("SELECT 1 FROM sqlite_master WHERE type=? AND name=?")?;
-        if !statement.exists(["table", "encounter"])? {
-            info!("creating tables");
-            migration_legacy_encounter(&transaction)?;
-            migration_legacy_entity(&transaction)?;
-        }
-    
-        // NOTE: for databases, where the bad migration code already ran
-        migration_legacy_entity(&transaction)?;
-    
-        if !statement.exists(["table", "encounter_preview"])? {
-            info!("optimizing searches");
-            migration_legacy_encounter(&transaction)?;
-            migration_legacy_entity(&transaction)?;
-            migration_full_text_search(&transaction)?;
-        }
-    
-        if !statement.exists(["table", "sync_logs"])? {
-            info!("adding sync table");
-            migration_sync(&transaction)?;
-        }
-    
-        migration_specs(&transaction)?;
-    
-        statement.finalize()?;
-        info!("finished setting up database");
-        
+        (migration.up)(&transaction).inspect_err(|err| {
+            error!("migration {} {} failed: {}", migration.version, migration.name, err);
+        })?;
+        record_migration(&transaction, migration)?;
         transaction.commit()?;
 
-        Ok(())
+        applied.push(AppliedMigration {
+            version: migration.version,
+            name: migration.name,
+        });
     }
+
+    info!("finished setting up database");
+
+    Ok(applied)
+}
+
+fn get_schema_version(connection: &Connection) -> Result<u32> {
+    let version = connection.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+        [],
+        |row| row.get(0))?;
+
+    Ok(version)
+}
+
+pub(crate) fn latest_schema_version() -> u32 {
+    MIGRATIONS.last().map(|migration| migration.version).unwrap_or_default()
+}
+
+fn ensure_supported(connection: &Connection, schema_version: u32) -> Result<()> {
+    // legacy databases may predate the column, `legacy_encounter` adds it
+    let has_encounter_version = connection
+        .query_row("SELECT 1 FROM pragma_table_info('encounter') WHERE name = 'version'", [], |_| Ok(()))
+        .optional()?
+        .is_some();
+
+    // the newest row is enough, scanning every encounter would read through all the blobs
+    let encounter_version: i32 = if has_encounter_version {
+        connection
+            .query_row("SELECT version FROM encounter ORDER BY id DESC LIMIT 1", [], |row| row.get(0))
+            .optional()?
+            .unwrap_or_default()
+    } else {
+        0
+    };
+
+    let supported_schema_version = latest_schema_version();
+
+    if schema_version > supported_schema_version || encounter_version > DB_VERSION {
+        return Err(SchemaTooNew {
+            schema_version,
+            supported_schema_version,
+            encounter_version,
+            supported_encounter_version: DB_VERSION,
+        }.into());
+    }
+
+    Ok(())
+}
+
+fn record_migration(transaction: &Transaction, migration: &Migration) -> Result<(), rusqlite::Error> {
+    transaction.execute(
+        "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, ?2, ?3)",
+        params![migration.version, migration.name, Utc::now().timestamp_millis()])?;
+
+    Ok(())
+}
+
+/// Databases created before the ledger existed carry no record of what ran.
+///
+/// Everything except `legacy_encounter` and `full_text_search` is safe to re-run, so only those two
+/// are marked as applied when `encounter_preview` shows that the legacy code already got that far.
+fn adopt_legacy_schema(transaction: &Transaction) -> Result<(), rusqlite::Error> {
+    let has_migrations: bool = transaction.query_row("SELECT EXISTS(SELECT 1 FROM schema_migrations)", [], |row| row.get(0))?;
+
+    if has_migrations {
+        return Ok(());
+    }
+
+    let mut statement = transaction.prepare("SELECT 1 FROM sqlite_master WHERE type=? AND name=?")?;
+
+    if statement.exists(["table", "encounter_preview"])? {
+        info!("adopting legacy schema");
+
+        for migration in MIGRATIONS.iter().filter(|migration| matches!(migration.version, 1 | 3)) {
+            record_migration(transaction, migration)?;
+        }
+    }
+
+    statement.finalize()
 }
 
 fn migration_legacy_encounter(transaction: &Transaction) -> Result<(), rusqlite::Error> {
@@ -94,12 +256,12 @@ fn migration_legacy_encounter(transaction: &Transaction) -> Result<(), rusqlite:
         transaction.execute("ALTER TABLE encounter ADD COLUMN difficulty TEXT", [])?;
     }
     if !stmt.exists(["encounter", "favorite"])? {
-        transaction.execute_batch(
+        transaction.execute_batch(&format!(
             "
             ALTER TABLE encounter ADD COLUMN favorite BOOLEAN DEFAULT 0;
             ALTER TABLE encounter ADD COLUMN version INTEGER DEFAULT {};
             ALTER TABLE encounter ADD COLUMN cleared BOOLEAN;
-            ")?;
+            ", DB_VERSION))?;
     }
     if !stmt.exists(["encounter", "boss_only_damage"])? {
         transaction.execute(
@@ -279,13 +441,135 @@ fn migration_specs(transaction: &Transaction) -> Result<(), rusqlite::Error> {
     stmt.finalize()
 }
 
+fn migration_participant_indexes(transaction: &Transaction) -> Result<(), rusqlite::Error> {
+    transaction.execute_batch(
+        "
+        CREATE INDEX IF NOT EXISTS entity_class_id_index
+        ON entity (class_id, encounter_id);
+        CREATE INDEX IF NOT EXISTS entity_character_id_index
+        ON entity (character_id, encounter_id);
+        ",
+    )
+}
+
+/// Structured replacement for the `class_id:name` list in `players`, backfilled from `entity`.
+///
+/// The backfill picks and orders players with the same code as `create`, so it can't be plain SQL.
+fn migration_preview_player_data(transaction: &Transaction) -> Result<(), rusqlite::Error> {
+    transaction.execute("ALTER TABLE encounter_preview ADD COLUMN player_data TEXT", [])?;
+
+    let previews = transaction
+        .prepare("SELECT id, local_player FROM encounter_preview")?
+        .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Option<String>>(1)?.unwrap_or_default())))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut statement = transaction.prepare("UPDATE encounter_preview SET player_data = ?2 WHERE id = ?1")?;
+
+    for (id, local_player) in previews {
+        // a damage_stats blob that doesn't decode must not keep the database from opening,
+        // previews left NULL fall back to the legacy players string
+        let players = match load_preview_players(transaction, id, &local_player) {
+            Ok(players) => players,
+            Err(err @ rusqlite::Error::FromSqlConversionFailure(..)) => {
+                warn!("could not backfill player data of encounter {}: {}", id, err);
+                continue;
+            },
+            Err(err) => return Err(err),
+        };
+
+        statement.execute(params![id, json!(players)])?;
+    }
+
+    Ok(())
+}
+
+/// Adds tags and notes, and recreates `encounter_search` with the note as a third column.
+///
+/// The index reads from a view joining the note to the preview, the triggers on both tables keep it in sync.
+fn migration_tags_and_notes(transaction: &Transaction) -> Result<(), rusqlite::Error> {
+    transaction.execute_batch(
+        "
+        CREATE TABLE encounter_tag (
+            encounter_id INTEGER NOT NULL,
+            tag TEXT NOT NULL,
+            PRIMARY KEY (encounter_id, tag),
+            FOREIGN KEY (encounter_id) REFERENCES encounter (id) ON DELETE CASCADE
+        );
+        CREATE INDEX encounter_tag_tag_index ON encounter_tag (tag, encounter_id);
+
+        CREATE TABLE encounter_note (
+            encounter_id INTEGER PRIMARY KEY,
+            note TEXT NOT NULL,
+            updated_at INTEGER NOT NULL,
+            FOREIGN KEY (encounter_id) REFERENCES encounter (id) ON DELETE CASCADE
+        );
+
+        DROP TRIGGER encounter_preview_ai;
+        DROP TRIGGER encounter_preview_ad;
+        DROP TRIGGER encounter_preview_au;
+        DROP TABLE encounter_search;
+
+        CREATE VIEW encounter_search_content AS
+        SELECT p.id, p.current_boss, p.players, n.note
+        FROM encounter_preview p
+        LEFT JOIN encounter_note n ON n.encounter_id = p.id;
+
+        CREATE VIRTUAL TABLE encounter_search USING fts5(
+            current_boss, players, note, columnsize=0, detail=full,
+            tokenize='trigram remove_diacritics 1',
+            content=encounter_search_content, content_rowid=id
+        );
+        INSERT INTO encounter_search(encounter_search) VALUES('rebuild');
+
+        CREATE TRIGGER encounter_preview_ai AFTER INSERT ON encounter_preview BEGIN
+            INSERT INTO encounter_search(rowid, current_boss, players, note)
+            VALUES (new.id, new.current_boss, new.players,
+                (SELECT note FROM encounter_note WHERE encounter_id = new.id));
+        END;
+        CREATE TRIGGER encounter_preview_ad AFTER DELETE ON encounter_preview BEGIN
+            INSERT INTO encounter_search(encounter_search, rowid, current_boss, players, note)
+            VALUES('delete', old.id, old.current_boss, old.players,
+                (SELECT note FROM encounter_note WHERE encounter_id = old.id));
+        END;
+        CREATE TRIGGER encounter_preview_au AFTER UPDATE OF current_boss, players ON encounter_preview BEGIN
+            INSERT INTO encounter_search(encounter_search, rowid, current_boss, players, note)
+            VALUES('delete', old.id, old.current_boss, old.players,
+                (SELECT note FROM encounter_note WHERE encounter_id = old.id));
+            INSERT INTO encounter_search(rowid, current_boss, players, note)
+            VALUES (new.id, new.current_boss, new.players,
+                (SELECT note FROM encounter_note WHERE encounter_id = new.id));
+        END;
+
+        CREATE TRIGGER encounter_note_ai AFTER INSERT ON encounter_note BEGIN
+            INSERT INTO encounter_search(encounter_search, rowid, current_boss, players, note)
+            SELECT 'delete', id, current_boss, players, NULL FROM encounter_preview WHERE id = new.encounter_id;
+            INSERT INTO encounter_search(rowid, current_boss, players, note)
+            SELECT id, current_boss, players, new.note FROM encounter_preview WHERE id = new.encounter_id;
+        END;
+        CREATE TRIGGER encounter_note_ad AFTER DELETE ON encounter_note BEGIN
+            INSERT INTO encounter_search(encounter_search, rowid, current_boss, players, note)
+            SELECT 'delete', id, current_boss, players, old.note FROM encounter_preview WHERE id = old.encounter_id;
+            INSERT INTO encounter_search(rowid, current_boss, players, note)
+            SELECT id, current_boss, players, NULL FROM encounter_preview WHERE id = old.encounter_id;
+        END;
+        CREATE TRIGGER encounter_note_au AFTER UPDATE OF note ON encounter_note BEGIN
+            INSERT INTO encounter_search(encounter_search, rowid, current_boss, players, note)
+            SELECT 'delete', id, current_boss, players, old.note FROM encounter_preview WHERE id = old.encounter_id;
+            INSERT INTO encounter_search(rowid, current_boss, players, note)
+            SELECT id, current_boss, players, new.note FROM encounter_preview WHERE id = new.encounter_id;
+        END;
+        ",
+    )
+}
 
 
 #[cfg(test)]
 mod tests {
     use std::{env, path::PathBuf, time::{SystemTime, UNIX_EPOCH}};
+    use lost_metrics_core::models::DamageStats;
+
     use crate::connection_pool;
-    use super::MigrationRunner;
+    use super::*;
 
     fn get_semi_random_db_path() -> PathBuf {
         let path = env::current_dir().unwrap();
@@ -300,9 +584,184 @@ mod tests {
     
     #[test]
     fn should_create_new_database() {
-        let connection_pool = connection_pool::in_memory();
+        let connection_pool = connection_pool::in_memory().unwrap();
         let migration_runner = MigrationRunner::new(connection_pool);
 
         migration_runner.run().unwrap();
     }
+    #[test]
+    fn should_apply_each_migration_once() {
+        let connection_pool = connection_pool::in_memory().unwrap();
+        let migration_runner = MigrationRunner::new(connection_pool.clone());
+
+        let applied = migration_runner.run().unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+
+        let applied = migration_runner.run().unwrap();
+        assert!(applied.is_empty());
+
+        let connection = connection_pool.get().unwrap();
+        assert_eq!(get_schema_version(&connection).unwrap(), latest_schema_version());
+    }
+
+    #[test]
+    fn should_refuse_newer_schema() {
+        let connection_pool = connection_pool::in_memory().unwrap();
+        let migration_runner = MigrationRunner::new(connection_pool.clone());
+        migration_runner.run().unwrap();
+
+        let connection = connection_pool.get().unwrap();
+        connection.execute(
+            "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, 'future', 0)",
+            [latest_schema_version() + 1]).unwrap();
+
+        let error = migration_runner.run().unwrap_err();
+        let StoreError::SchemaTooNew(error) = error else {
+            panic!("unexpected error {}", error);
+        };
+        assert_eq!(error.schema_version, latest_schema_version() + 1);
+    }
+
+    #[test]
+    fn should_refuse_newer_encounter_version() {
+        let connection_pool = connection_pool::in_memory().unwrap();
+        let migration_runner = MigrationRunner::new(connection_pool.clone());
+        migration_runner.run().unwrap();
+
+        let connection = connection_pool.get().unwrap();
+        connection.execute("INSERT INTO encounter (version) VALUES (?1)", [DB_VERSION + 1]).unwrap();
+
+        let error = migration_runner.run().unwrap_err();
+        assert!(matches!(error, StoreError::SchemaTooNew(_)));
+    }
+
+    #[test]
+    fn should_check_without_migrating() {
+        let connection_pool = connection_pool::in_memory().unwrap();
+        let migration_runner = MigrationRunner::new(connection_pool.clone());
+
+        assert_eq!(migration_runner.check().unwrap(), 0);
+
+        let tables: i64 = connection_pool.get().unwrap()
+            .query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(tables, 0);
+
+        migration_runner.run().unwrap();
+        assert_eq!(migration_runner.check().unwrap(), latest_schema_version());
+    }
+
+    #[test]
+    fn should_migrate_encounters_without_version() {
+        let connection_pool = connection_pool::in_memory().unwrap();
+        let connection = connection_pool.get().unwrap();
+        connection.execute_batch(
+            "
+            CREATE TABLE encounter (
+                id INTEGER PRIMARY KEY,
+                last_combat_packet INTEGER,
+                fight_start INTEGER,
+                local_player TEXT,
+                current_boss TEXT,
+                duration INTEGER,
+                total_damage_dealt INTEGER,
+                top_damage_dealt INTEGER,
+                total_damage_taken INTEGER,
+                top_damage_taken INTEGER,
+                dps INTEGER,
+                buffs TEXT,
+                debuffs TEXT
+            );
+            INSERT INTO encounter (fight_start, current_boss, duration) VALUES (1000, 'Narok the Butcher', 600000);
+            ").unwrap();
+
+        let migration_runner = MigrationRunner::new(connection_pool.clone());
+        assert_eq!(migration_runner.check().unwrap(), 0);
+        migration_runner.run().unwrap();
+
+        let version: i32 = connection.query_row("SELECT version FROM encounter", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, DB_VERSION);
+    }
+
+    #[test]
+    fn should_backfill_player_data_like_create() {
+        let connection_pool = connection_pool::in_memory().unwrap();
+        let mut connection = connection_pool.get().unwrap();
+
+        let transaction = connection.transaction().unwrap();
+        for migration in MIGRATIONS.iter().filter(|migration| migration.version < 7) {
+            (migration.up)(&transaction).unwrap();
+        }
+
+        transaction.execute("INSERT INTO encounter (id) VALUES (1)", []).unwrap();
+        transaction.execute("INSERT INTO encounter_preview (id, local_player, players) VALUES (1, 'alice', '')", []).unwrap();
+        for (name, entity_type, damage_dealt, dps) in [("alice", "PLAYER", 100, 50), ("bob", "PLAYER", 300, 40), ("Narok", "BOSS", 1000, 0)] {
+            let damage_stats = lost_metrics_misc::compress_json(&DamageStats { damage_dealt, dps, ..Default::default() });
+            transaction.execute(
+                "INSERT INTO entity (name, encounter_id, entity_type, class_id, dps, damage_stats) VALUES (?1, 1, ?2, 204, ?3, ?4)",
+                params![name, entity_type, dps, damage_stats]).unwrap();
+        }
+
+        migration_preview_player_data(&transaction).unwrap();
+
+        let player_data: serde_json::Value = transaction
+            .query_row("SELECT player_data FROM encounter_preview WHERE id = 1", [], |row| row.get(0))
+            .unwrap();
+        let names: Vec<_> = player_data.as_array().unwrap().iter().map(|player| player["name"].clone()).collect();
+        assert_eq!(names, vec![json!("bob"), json!("alice")]);
+    }
+
+    #[test]
+    fn should_skip_undecodable_player_data() {
+        let connection_pool = connection_pool::in_memory().unwrap();
+        let mut connection = connection_pool.get().unwrap();
+
+        let transaction = connection.transaction().unwrap();
+        for migration in MIGRATIONS.iter().filter(|migration| migration.version < 7) {
+            (migration.up)(&transaction).unwrap();
+        }
+
+        let damage_stats = lost_metrics_misc::compress_json(&DamageStats { damage_dealt: 100, dps: 50, ..Default::default() });
+        for (id, damage_stats) in [(1, b"not gzip".to_vec()), (2, damage_stats)] {
+            transaction.execute("INSERT INTO encounter (id) VALUES (?1)", [id]).unwrap();
+            transaction.execute("INSERT INTO encounter_preview (id, local_player, players) VALUES (?1, 'alice', '204:alice')", [id]).unwrap();
+            transaction.execute(
+                "INSERT INTO entity (name, encounter_id, entity_type, class_id, dps, damage_stats) VALUES ('alice', ?1, 'PLAYER', 204, 50, ?2)",
+                params![id, damage_stats]).unwrap();
+        }
+
+        migration_preview_player_data(&transaction).unwrap();
+
+        let player_data = |id: i64| -> Option<String> {
+            transaction
+                .query_row("SELECT player_data FROM encounter_preview WHERE id = ?1", [id], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(player_data(1), None);
+        assert!(player_data(2).is_some());
+    }
+
+    #[test]
+    fn should_adopt_legacy_database() {
+        let connection_pool = connection_pool::in_memory().unwrap();
+
+        {
+            let mut connection = connection_pool.get().unwrap();
+            let transaction = connection.transaction().unwrap();
+            migration_legacy_encounter(&transaction).unwrap();
+            migration_legacy_entity(&transaction).unwrap();
+            migration_full_text_search(&transaction).unwrap();
+            transaction.commit().unwrap();
+        }
+
+        let migration_runner = MigrationRunner::new(connection_pool);
+        let applied: Vec<_> = migration_runner.run().unwrap()
+            .into_iter()
+            .map(|migration| migration.version)
+            .collect();
+
+        assert!(!applied.contains(&1));
+        assert!(!applied.contains(&3));
+        assert!(applied.contains(&4));
+    }
 }
\ No newline at end of file
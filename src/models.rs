@@ -1,10 +1,13 @@
 
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, str::FromStr};
 
 use hashbrown::HashMap;
 use lost_metrics_core::models::*;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::error::StoreError;
+
 pub struct CreateEncounter {
     pub encounter: Encounter,
     pub prev_stagger: i32,
@@ -48,7 +51,8 @@ pub struct EncounterPreviewDb<'a> {
     pub fight_start: i64,
     pub current_boss_name: &'a str,
     pub duration: i64,
-    pub preview_players: &'a str,
+    /// Sorted by damage dealt, highest first.
+    pub players: Vec<PreviewPlayer>,
     pub raid_difficulty: &'a str,
     pub local_player: &'a str,
     pub local_player_dps: i64,
@@ -82,4 +86,375 @@ pub struct EntityDb<'a> {
     pub skill_stats_json: Value,
     pub engraving_data_json: Value,
     pub ark_passive_data_json: Value
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviewPlayer {
+    pub name: String,
+    pub class_id: u32,
+    pub spec: Option<String>,
+    pub gear_score: f32,
+    pub dps: i64,
+}
+
+/// Preview filter, [`SearchFilter`] plus the constraints it has no fields for.
+///
+/// Serialized with the [`SearchFilter`] fields inline, the others can be left out.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct EncounterFilter {
+    #[serde(flatten)]
+    pub base: SearchFilter,
+    /// Inclusive bounds on `fight_start`, in milliseconds.
+    pub fight_start_from: Option<i64>,
+    pub fight_start_to: Option<i64>,
+    /// In seconds, like [`SearchFilter::min_duration`].
+    pub max_duration: Option<i64>,
+    /// Matches any of these, together with [`SearchFilter::difficulty`] when set.
+    pub difficulties: Vec<String>,
+    pub participants: Vec<Participant>,
+    pub participant_match: ParticipantMatch,
+    /// Matches encounters that have any of these tags.
+    pub tags: Vec<String>,
+    /// Region the encounter was recorded in, as stored in the encounter's misc data.
+    pub region: Option<String>,
+}
+
+/// A player that took part in the encounter, every field that is set has to match the same entity.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Participant {
+    pub name: Option<String>,
+    pub class_id: Option<u32>,
+    pub character_id: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ParticipantMatch {
+    #[default]
+    Any,
+    All,
+}
+
+impl From<SearchFilter> for EncounterFilter {
+    fn from(base: SearchFilter) -> Self {
+        Self {
+            base,
+            ..Default::default()
+        }
+    }
+}
+
+/// Columns of `encounter_preview` that previews can be sorted by.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SortColumn {
+    #[default]
+    FightStart,
+    Duration,
+    MyDps,
+    CurrentBoss,
+    Difficulty,
+}
+
+impl SortColumn {
+    pub fn column(self) -> &'static str {
+        match self {
+            SortColumn::FightStart => "fight_start",
+            SortColumn::Duration => "duration",
+            SortColumn::MyDps => "my_dps",
+            SortColumn::CurrentBoss => "current_boss",
+            SortColumn::Difficulty => "difficulty",
+        }
+    }
+
+    /// Sort key with `NULL`s folded into a value, keyset comparisons never match `NULL`.
+    pub fn expression(self) -> &'static str {
+        match self {
+            SortColumn::FightStart => "e.fight_start",
+            SortColumn::Duration => "e.duration",
+            SortColumn::MyDps => "IFNULL(e.my_dps, 0)",
+            SortColumn::CurrentBoss => "IFNULL(e.current_boss, '')",
+            SortColumn::Difficulty => "IFNULL(e.difficulty, '')",
+        }
+    }
+}
+
+impl FromStr for SortColumn {
+    type Err = StoreError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "" | "fight_start" => Ok(SortColumn::FightStart),
+            "duration" => Ok(SortColumn::Duration),
+            "my_dps" => Ok(SortColumn::MyDps),
+            "current_boss" => Ok(SortColumn::CurrentBoss),
+            "difficulty" => Ok(SortColumn::Difficulty),
+            _ => Err(StoreError::InvalidFilter(format!("unknown sort column '{}'", value))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TagSummary {
+    pub tag: String,
+    pub encounters: i64,
+}
+
+/// Aggregates over the previews of one boss and difficulty.
+///
+/// Durations are over cleared attempts only, dps over attempts where the local player dealt damage.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BossStats {
+    pub boss: String,
+    pub difficulty: String,
+    pub attempts: i64,
+    pub clears: i64,
+    pub clear_rate: f64,
+    pub average_clear_duration: Option<i64>,
+    pub best_clear_duration: Option<i64>,
+    pub best_dps: Option<i64>,
+    pub median_dps: Option<i64>,
+}
+
+/// Whose personal best to look up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PersonalBestOf {
+    /// The local player on any character of this class.
+    Class(u32),
+    Character(u64),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersonalBestEntry {
+    pub encounter_id: i64,
+    pub fight_start: i64,
+    /// Dps, or the duration in milliseconds for clear times.
+    pub value: i64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersonalBest {
+    pub dps: Option<PersonalBestEntry>,
+    pub clear_duration: Option<PersonalBestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatedEncounter {
+    pub id: i64,
+    /// The local player beat their previous best dps on this boss and difficulty.
+    pub new_dps_best: bool,
+    /// The encounter was cleared faster than any previous clear the local player took part in.
+    pub new_clear_best: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DpsPoint {
+    pub encounter_id: i64,
+    pub fight_start: i64,
+    pub class_id: u32,
+    pub spec: Option<String>,
+    pub dps: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BossDpsSeries {
+    pub boss: String,
+    pub difficulty: String,
+    /// Oldest first.
+    pub points: Vec<DpsPoint>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GearScorePoint {
+    pub encounter_id: i64,
+    pub fight_start: i64,
+    pub gear_score: f32,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CharacterHistory {
+    pub dps: Vec<BossDpsSeries>,
+    /// Oldest first, only the encounters where the gear score changed.
+    pub gear_score: Vec<GearScorePoint>,
+}
+
+/// Outcome of the last upload of an encounter, encounters without one were never uploaded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncLog {
+    pub encounter_id: i64,
+    pub upstream_id: Option<String>,
+    pub failed: bool,
+}
+
+/// Portable copy of one encounter, written by `EncounterService::export_encounter` as json.
+///
+/// ```json
+/// {
+///     "format": "lost-metrics-encounter",
+///     "version": 1,
+///     "dbVersion": 5,
+///     "exportedAt": 1700000000000,
+///     "encounter": { ... }
+/// }
+/// ```
+///
+/// `version` changes whenever this layout does, `dbVersion` is the `DB_VERSION` of the exporting build.
+/// `encounter` is the [`Encounter`] as returned by `load_encounter`, including `favorite` and `cleared`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncounterExport {
+    pub format: String,
+    pub version: u32,
+    pub db_version: i32,
+    pub exported_at: i64,
+    pub encounter: Encounter,
+}
+
+impl EncounterExport {
+    pub const FORMAT: &'static str = "lost-metrics-encounter";
+    pub const VERSION: u32 = 1;
+}
+
+/// Which encounters to prune. Favorites, clears and tagged encounters are always kept.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionPolicy {
+    /// Prunes encounters that started more than this many days ago.
+    pub max_age_days: Option<u32>,
+    /// Keeps only this many of the most recent encounters of each boss, older ones are pruned.
+    pub max_per_boss: Option<u32>,
+    /// Prunes the oldest encounters until the database is under this budget, measured again after each round of deletes.
+    /// A dry run can't measure and only reports the first round, picked by estimated encounter size.
+    pub max_size_bytes: Option<u64>,
+    /// Only reports what would be pruned.
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionReport {
+    /// Pruned encounters, or the ones that would be on a dry run.
+    pub encounter_ids: Vec<i64>,
+    pub dry_run: bool,
+}
+
+/// Space used by a table or index, FTS shadow tables included.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TableSize {
+    pub name: String,
+    pub bytes: i64,
+}
+
+/// Total length of the values in a blob or json column.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ColumnSize {
+    pub table: String,
+    pub column: String,
+    pub bytes: i64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageStats {
+    /// Size of the database pages in use.
+    pub used_bytes: i64,
+    /// Size of the free pages, released by an incremental vacuum.
+    pub free_bytes: i64,
+    /// Largest first.
+    pub tables: Vec<TableSize>,
+    pub columns: Vec<ColumnSize>,
+}
+
+/// Estimated space taken by an encounter, the length of its blobs and those of its entities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncounterCost {
+    pub encounter_id: i64,
+    pub bytes: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ForeignKeyViolation {
+    pub table: String,
+    pub rowid: Option<i64>,
+    pub parent: String,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrityReport {
+    /// Messages from `PRAGMA integrity_check`, empty when it found nothing.
+    pub integrity_errors: Vec<String>,
+    pub foreign_key_violations: Vec<ForeignKeyViolation>,
+    pub search_index_ok: bool,
+    /// Ids of missing encounters that still have entities.
+    pub orphaned_entities: Vec<i64>,
+    pub orphaned_previews: Vec<i64>,
+    pub encounters_without_preview: Vec<i64>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.integrity_errors.is_empty()
+            && self.foreign_key_violations.is_empty()
+            && self.search_index_ok
+            && self.orphaned_entities.is_empty()
+            && self.orphaned_previews.is_empty()
+            && self.encounters_without_preview.is_empty()
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepairReport {
+    /// Ids of missing encounters whose entities or preview were deleted,
+    /// like [`IntegrityReport::orphaned_entities`] and [`IntegrityReport::orphaned_previews`].
+    pub deleted_orphans: Vec<i64>,
+    /// Rows deleted for the [`IntegrityReport::foreign_key_violations`] left after that.
+    pub deleted_foreign_key_violations: usize,
+    /// Encounters whose missing preview was created again, like [`IntegrityReport::encounters_without_preview`].
+    pub regenerated_previews: Vec<i64>,
+}
+
+/// [`EncounterPreview`] along with the per-player data it has no fields for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncounterPreviewItem {
+    pub preview: EncounterPreview,
+    pub players: Vec<PreviewPlayer>,
+}
+
+/// One page of previews from offset pagination, see [`EncounterPreviewPage`] for keyset pagination.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncounterPreviewOverview {
+    pub encounters: Vec<EncounterPreviewItem>,
+    pub total_encounters: i32,
+}
+
+/// One page of previews from keyset pagination.
+///
+/// Cursors are opaque, pass them back unchanged along with the same search and filter.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncounterPreviewPage {
+    pub encounters: Vec<EncounterPreviewItem>,
+    pub next_cursor: Option<String>,
+    pub prev_cursor: Option<String>,
+    pub total_encounters: Option<i32>,
+}
@@ -0,0 +1,84 @@
+use rusqlite::{params, Connection};
+
+use crate::error::*;
+
+use super::{queries::{DELETE_ENCOUNTER, DELETE_ENCOUNTER_PREVIEW, DELETE_ENTITIES, DELETE_SYNC_LOG}, SqliteRepository};
+
+impl SqliteRepository {
+
+    /// Removes the encounters along with their entities, preview and sync log.
+    ///
+    /// Returns the number of encounters that existed and were deleted.
+    pub(crate) fn delete_encounters_inner(
+        &self,
+        connection: &Connection,
+        ids: &[i64]) -> Result<usize> {
+
+        let mut delete_sync_log = connection.prepare_cached(DELETE_SYNC_LOG)?;
+        let mut delete_entities = connection.prepare_cached(DELETE_ENTITIES)?;
+        let mut delete_encounter_preview = connection.prepare_cached(DELETE_ENCOUNTER_PREVIEW)?;
+        let mut delete_encounter = connection.prepare_cached(DELETE_ENCOUNTER)?;
+
+        let mut deleted = 0;
+
+        for id in ids {
+            delete_sync_log.execute(params![id])?;
+            delete_entities.execute(params![id])?;
+            delete_encounter_preview.execute(params![id])?;
+            deleted += delete_encounter.execute(params![id])?;
+        }
+
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repository::{test_utils::*, Repository};
+
+    #[test]
+    fn should_delete_encounter_and_children() {
+        let repository = setup();
+        let kept_id = TestEncounter::default().insert(&repository);
+        let deleted_id = TestEncounter::default().insert(&repository);
+
+        let connection = repository.get_connection().unwrap();
+        connection.execute("INSERT INTO sync_logs (encounter_id, upstream_id) VALUES (?1, 'upstream')", [deleted_id]).unwrap();
+
+        let deleted = repository.delete_encounters(&connection, &[deleted_id]).unwrap();
+        assert_eq!(deleted, 1);
+
+        for table in ["encounter", "encounter_preview"] {
+            let count: i64 = connection
+                .query_row(&format!("SELECT COUNT(*) FROM {} WHERE id = ?1", table), [deleted_id], |row| row.get(0))
+                .unwrap();
+            assert_eq!(count, 0, "{} row left behind", table);
+        }
+
+        for table in ["entity", "sync_logs"] {
+            let count: i64 = connection
+                .query_row(&format!("SELECT COUNT(*) FROM {} WHERE encounter_id = ?1", table), [deleted_id], |row| row.get(0))
+                .unwrap();
+            assert_eq!(count, 0, "{} row left behind", table);
+        }
+
+        let matches: i64 = connection
+            .query_row("SELECT COUNT(*) FROM encounter_search WHERE encounter_search MATCH 'Narok'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(matches, 1);
+
+        let kept: i64 = connection
+            .query_row("SELECT COUNT(*) FROM encounter_preview WHERE id = ?1", [kept_id], |row| row.get(0))
+            .unwrap();
+        assert_eq!(kept, 1);
+    }
+
+    #[test]
+    fn should_ignore_unknown_ids() {
+        let repository = setup();
+        let connection = repository.get_connection().unwrap();
+
+        let deleted = repository.delete_encounters(&connection, &[1, 2, 3]).unwrap();
+        assert_eq!(deleted, 0);
+    }
+}
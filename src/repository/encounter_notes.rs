@@ -0,0 +1,109 @@
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::error::*;
+
+use super::{queries::{DELETE_NOTE, SELECT_NOTE, UPSERT_NOTE}, SqliteRepository};
+
+impl SqliteRepository {
+
+    /// Replaces the note of the encounter, the `encounter_search` index is updated by the triggers on `encounter_note`.
+    pub(crate) fn set_note_inner(
+        &self,
+        connection: &Connection,
+        encounter_id: i64,
+        note: &str) -> Result<()> {
+        Self::ensure_encounter_exists(connection, encounter_id)?;
+
+        let mut statement = connection.prepare_cached(UPSERT_NOTE)?;
+        statement.execute(params![encounter_id, note, Utc::now().timestamp_millis()])?;
+
+        Ok(())
+    }
+
+    pub(crate) fn delete_note_inner(
+        &self,
+        connection: &Connection,
+        encounter_id: i64) -> Result<()> {
+        Self::ensure_encounter_exists(connection, encounter_id)?;
+
+        let mut statement = connection.prepare_cached(DELETE_NOTE)?;
+        statement.execute(params![encounter_id])?;
+
+        Ok(())
+    }
+
+    pub(crate) fn load_note_inner(&self, encounter_id: i64) -> Result<Option<String>> {
+        let connection = self.pool.get()?;
+        Self::ensure_encounter_exists(&connection, encounter_id)?;
+
+        let mut statement = connection.prepare_cached(SELECT_NOTE)?;
+        let note = statement
+            .query_row(params![encounter_id], |row| row.get(0))
+            .optional()?;
+
+        Ok(note)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lost_metrics_core::models::SearchFilter;
+
+    use crate::{error::StoreError, repository::{test_utils::*, Repository}};
+
+    fn search(repository: &impl Repository, search: &str) -> Vec<i64> {
+        repository.load_encounters_preview(1, 10, search.into(), SearchFilter::default().into()).unwrap()
+            .encounters
+            .iter()
+            .map(|encounter| encounter.preview.id as i64)
+            .collect()
+    }
+
+    #[test]
+    fn should_set_update_and_delete_note() {
+        let repository = setup();
+        let id = TestEncounter::default().insert(&repository);
+        let connection = repository.get_connection().unwrap();
+
+        assert_eq!(repository.load_note(id).unwrap(), None);
+
+        repository.set_note(&connection, id, "wiped on gate 2").unwrap();
+        repository.set_note(&connection, id, "cleared with a pug").unwrap();
+        assert_eq!(repository.load_note(id).unwrap(), Some("cleared with a pug".into()));
+
+        repository.delete_note(&connection, id).unwrap();
+        assert_eq!(repository.load_note(id).unwrap(), None);
+    }
+
+    #[test]
+    fn should_search_notes() {
+        let repository = setup();
+        let id = TestEncounter::default().insert(&repository);
+        TestEncounter::default().insert(&repository);
+        let connection = repository.get_connection().unwrap();
+
+        repository.set_note(&connection, id, "wiped on gate 2").unwrap();
+        assert_eq!(search(&repository, "gate"), vec![id]);
+
+        repository.set_note(&connection, id, "cleared with a pug").unwrap();
+        assert!(search(&repository, "gate").is_empty());
+        assert_eq!(search(&repository, "pug"), vec![id]);
+
+        repository.delete_note(&connection, id).unwrap();
+        assert!(search(&repository, "pug").is_empty());
+        assert_eq!(search(&repository, "Narok").len(), 2);
+
+        let integrity = connection.execute("INSERT INTO encounter_search(encounter_search) VALUES('integrity-check')", []);
+        assert!(integrity.is_ok());
+    }
+
+    #[test]
+    fn should_fail_to_set_note_on_missing_encounter() {
+        let repository = setup();
+        let connection = repository.get_connection().unwrap();
+
+        let result = repository.set_note(&connection, 404, "note");
+        assert!(matches!(result, Err(StoreError::NotFound(404))));
+    }
+}
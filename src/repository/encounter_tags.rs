@@ -0,0 +1,141 @@
+use rusqlite::{params, Connection};
+
+use crate::{error::*, models::TagSummary};
+
+use super::{queries::{DELETE_TAG, INSERT_TAG, SELECT_TAGS, SELECT_TAG_SUMMARIES}, SqliteRepository};
+
+impl SqliteRepository {
+
+    /// Tags are trimmed, blank ones and ones the encounter already has are skipped.
+    pub(crate) fn add_tags_inner(
+        &self,
+        connection: &Connection,
+        encounter_id: i64,
+        tags: &[String]) -> Result<()> {
+        Self::ensure_encounter_exists(connection, encounter_id)?;
+
+        let mut statement = connection.prepare_cached(INSERT_TAG)?;
+
+        for tag in tags.iter().map(|tag| tag.trim()).filter(|tag| !tag.is_empty()) {
+            statement.execute(params![encounter_id, tag])?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of tags that were removed.
+    pub(crate) fn remove_tags_inner(
+        &self,
+        connection: &Connection,
+        encounter_id: i64,
+        tags: &[String]) -> Result<usize> {
+        Self::ensure_encounter_exists(connection, encounter_id)?;
+
+        let mut statement = connection.prepare_cached(DELETE_TAG)?;
+        let mut removed = 0;
+
+        for tag in tags {
+            removed += statement.execute(params![encounter_id, tag.trim()])?;
+        }
+
+        Ok(removed)
+    }
+
+    pub(crate) fn load_tags_inner(&self, encounter_id: i64) -> Result<Vec<String>> {
+        let connection = self.pool.get()?;
+        Self::ensure_encounter_exists(&connection, encounter_id)?;
+
+        let mut statement = connection.prepare_cached(SELECT_TAGS)?;
+
+        let tags = statement
+            .query_map(params![encounter_id], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(tags)
+    }
+
+    pub(crate) fn load_tag_summaries_inner(&self) -> Result<Vec<TagSummary>> {
+        let connection = self.pool.get()?;
+        let mut statement = connection.prepare_cached(SELECT_TAG_SUMMARIES)?;
+
+        let summaries = statement
+            .query_map([], |row| Ok(TagSummary {
+                tag: row.get(0)?,
+                encounters: row.get(1)?,
+            }))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(summaries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lost_metrics_core::models::SearchFilter;
+
+    use crate::{error::StoreError, models::{EncounterFilter, TagSummary}, repository::{test_utils::*, Repository}};
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn should_add_and_remove_tags() {
+        let repository = setup();
+        let first = TestEncounter::default().insert(&repository);
+        let second = TestEncounter::default().insert(&repository);
+        let connection = repository.get_connection().unwrap();
+
+        repository.add_tags(&connection, first, &tags(&["prog", " reclear ", ""])).unwrap();
+        repository.add_tags(&connection, first, &tags(&["prog"])).unwrap();
+        repository.add_tags(&connection, second, &tags(&["prog"])).unwrap();
+
+        assert_eq!(repository.load_tags(first).unwrap(), tags(&["prog", "reclear"]));
+        assert_eq!(repository.load_tag_summaries().unwrap(), vec![
+            TagSummary { tag: "prog".into(), encounters: 2 },
+            TagSummary { tag: "reclear".into(), encounters: 1 },
+        ]);
+
+        let removed = repository.remove_tags(&connection, first, &tags(&["prog", "bad pug"])).unwrap();
+        assert_eq!(removed, 1);
+        assert_eq!(repository.load_tags(first).unwrap(), tags(&["reclear"]));
+    }
+
+    #[test]
+    fn should_fail_to_tag_missing_encounter() {
+        let repository = setup();
+        let connection = repository.get_connection().unwrap();
+
+        let result = repository.add_tags(&connection, 404, &tags(&["prog"]));
+        assert!(matches!(result, Err(StoreError::NotFound(404))));
+    }
+
+    #[test]
+    fn should_filter_previews_by_tag() {
+        let repository = setup();
+        let prog = TestEncounter::default().insert(&repository);
+        let reclear = TestEncounter::default().insert(&repository);
+        TestEncounter::default().insert(&repository);
+        let connection = repository.get_connection().unwrap();
+
+        repository.add_tags(&connection, prog, &tags(&["prog"])).unwrap();
+        repository.add_tags(&connection, reclear, &tags(&["reclear"])).unwrap();
+
+        let filter = EncounterFilter {
+            base: SearchFilter {
+                sort: "fight_start".into(),
+                order: 1,
+                ..Default::default()
+            },
+            tags: tags(&["prog", "reclear"]),
+            ..Default::default()
+        };
+        let ids: Vec<_> = repository.load_encounters_preview(1, 10, "".into(), filter).unwrap()
+            .encounters
+            .iter()
+            .map(|encounter| encounter.preview.id as i64)
+            .collect();
+
+        assert_eq!(ids, vec![prog, reclear]);
+    }
+}
@@ -1,7 +1,6 @@
 use rusqlite::{params, Connection};
-use anyhow::*;
 
-use crate::models::EncounterDb;
+use crate::{error::*, models::EncounterDb};
 
 use super::{queries::INSERT_ENCOUNTER, SqliteRepository};
 
@@ -1,7 +1,7 @@
-use anyhow::*;
 use rusqlite::{params, Connection};
+use serde_json::json;
 
-use crate::models::EncounterPreviewDb;
+use crate::{error::*, models::EncounterPreviewDb, utils::to_players_column};
 
 use super::{queries::INSERT_ENCOUNTER_PREVIEW, SqliteRepository};
 
@@ -15,17 +15,21 @@ impl SqliteRepository {
         
         let mut statement = connection.prepare_cached(INSERT_ENCOUNTER_PREVIEW)?;
 
+        let players = to_players_column(&encounter_preview.players);
+        let player_data = json!(encounter_preview.players);
+
         let params = params![
             encounter_id,
             encounter_preview.fight_start,
             encounter_preview.current_boss_name,
             encounter_preview.duration,
-            encounter_preview.preview_players,
+            players,
             encounter_preview.raid_difficulty,
             encounter_preview.local_player,
             encounter_preview.local_player_dps,
             encounter_preview.raid_clear,
-            encounter_preview.boss_only_damage
+            encounter_preview.boss_only_damage,
+            player_data
         ];
 
         statement.execute(params)?;
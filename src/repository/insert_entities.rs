@@ -1,7 +1,6 @@
 use rusqlite::{params, Connection};
-use anyhow::*;
 
-use crate::models::EntityDb;
+use crate::{error::*, models::EntityDb};
 
 use super::{queries::INSERT_ENTITIES, SqliteRepository};
 
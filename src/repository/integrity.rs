@@ -0,0 +1,176 @@
+use rusqlite::{Connection, ErrorCode};
+
+use crate::{error::*, models::{ForeignKeyViolation, IntegrityReport, RepairReport}};
+
+use super::{queries::*, SqliteRepository};
+
+impl SqliteRepository {
+
+    pub(crate) fn check_integrity_inner(&self) -> Result<IntegrityReport> {
+        let connection = self.pool.get()?;
+
+        let mut statement = connection.prepare("PRAGMA integrity_check")?;
+        let integrity_errors = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .filter(|message| !matches!(message.as_deref(), Ok("ok")))
+            .collect::<Result<_, _>>()?;
+
+        let foreign_key_violations = Self::load_foreign_key_violations(&connection)?;
+
+        let search_index_ok = match connection.execute(
+            "INSERT INTO encounter_search(encounter_search, rank) VALUES('integrity-check', 1)", []) {
+            Ok(_) => true,
+            Err(err) if err.sqlite_error_code() == Some(ErrorCode::DatabaseCorrupt) => false,
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(IntegrityReport {
+            integrity_errors,
+            foreign_key_violations,
+            search_index_ok,
+            orphaned_entities: Self::load_ids_with(&connection, SELECT_ORPHANED_ENTITIES)?,
+            orphaned_previews: Self::load_ids_with(&connection, SELECT_ORPHANED_PREVIEWS)?,
+            encounters_without_preview: Self::load_ids_with(&connection, SELECT_ENCOUNTERS_WITHOUT_PREVIEW)?,
+        })
+    }
+
+    /// Deletes the rows whose parent is gone, creates the missing previews again from `encounter`
+    /// and `entity`, then rebuilds `encounter_search`.
+    pub(crate) fn repair_inner(&self, connection: &Connection) -> Result<RepairReport> {
+        let mut deleted_orphans = Self::load_ids_with(connection, SELECT_ORPHANED_ENTITIES)?;
+        deleted_orphans.extend(Self::load_ids_with(connection, SELECT_ORPHANED_PREVIEWS)?);
+        deleted_orphans.sort_unstable();
+        deleted_orphans.dedup();
+
+        connection.execute(DELETE_ORPHANED_ENTITIES, [])?;
+        connection.execute(DELETE_ORPHANED_PREVIEWS, [])?;
+
+        let mut deleted_foreign_key_violations = 0;
+
+        for violation in Self::load_foreign_key_violations(connection)? {
+            if let Some(rowid) = violation.rowid {
+                let query = format!("DELETE FROM \"{}\" WHERE rowid = ?1", violation.table.replace('"', "\"\""));
+                deleted_foreign_key_violations += connection.execute(&query, [rowid])?;
+            }
+        }
+
+        let regenerated_previews = Self::load_ids_with(connection, SELECT_ENCOUNTERS_WITHOUT_PREVIEW)?;
+        self.rebuild_previews_inner(connection, Some(&regenerated_previews))?;
+
+        connection.execute("INSERT INTO encounter_search(encounter_search) VALUES('rebuild')", [])?;
+
+        Ok(RepairReport {
+            deleted_orphans,
+            deleted_foreign_key_violations,
+            regenerated_previews,
+        })
+    }
+
+    fn load_foreign_key_violations(connection: &Connection) -> Result<Vec<ForeignKeyViolation>> {
+        let mut statement = connection.prepare("PRAGMA foreign_key_check")?;
+
+        let violations = statement
+            .query_map([], |row| Ok(ForeignKeyViolation {
+                table: row.get(0)?,
+                rowid: row.get(1)?,
+                parent: row.get(2)?,
+            }))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(violations)
+    }
+
+    fn load_ids_with(connection: &Connection, query: &str) -> Result<Vec<i64>> {
+        let mut statement = connection.prepare_cached(query)?;
+
+        let ids = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lost_metrics_core::models::SearchFilter;
+
+    use crate::repository::{test_utils::*, Repository};
+
+    #[test]
+    fn should_report_healthy_database() {
+        let repository = setup();
+        TestEncounter::default().insert(&repository);
+
+        let report = repository.check_integrity().unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+    }
+
+    #[test]
+    fn should_list_regenerated_previews() {
+        let repository = setup();
+        let without_dps = TestEncounter { fight_start: 1_000, ..Default::default() }.insert(&repository);
+        let with_ntp_start = TestEncounter { fight_start: 2_000, ..Default::default() }.insert(&repository);
+
+        let mut connection = repository.get_connection().unwrap();
+        connection.execute(
+            "UPDATE encounter SET misc = json_set(misc, '$.ntpFightStart', 5000) WHERE id = ?1",
+            [with_ntp_start]).unwrap();
+        connection.execute("DELETE FROM encounter_preview", []).unwrap();
+
+        let transaction = connection.transaction().unwrap();
+        let repaired = repository.repair(&transaction).unwrap();
+        transaction.commit().unwrap();
+        assert_eq!(repaired.regenerated_previews, vec![without_dps, with_ntp_start]);
+
+        let page = repository.load_encounters_preview_page(None, 10, "".into(), SearchFilter::default().into(), false).unwrap();
+        assert_eq!(page.encounters.len(), 2);
+
+        let fight_start = |id: i64| page.encounters
+            .iter()
+            .find(|item| item.preview.id as i64 == id)
+            .map(|item| item.preview.fight_start)
+            .unwrap();
+        assert_eq!(fight_start(with_ntp_start), 5_000);
+        assert!(page.encounters.iter().all(|item| item.preview.duration >= 1_000));
+    }
+
+    #[test]
+    fn should_find_and_repair_orphans() {
+        let repository = setup();
+        let kept = TestEncounter::default().insert(&repository);
+        let without_encounter = TestEncounter::default().insert(&repository);
+        let without_preview = TestEncounter::default().insert(&repository);
+
+        let mut connection = repository.get_connection().unwrap();
+        connection.execute_batch("PRAGMA foreign_keys = OFF").unwrap();
+        connection.execute("INSERT INTO encounter_tag (encounter_id, tag) VALUES (?1, 'prog')", [without_encounter]).unwrap();
+        connection.execute("DELETE FROM encounter WHERE id = ?1", [without_encounter]).unwrap();
+        connection.execute("DELETE FROM encounter_preview WHERE id = ?1", [without_preview]).unwrap();
+        connection.execute(
+            "INSERT INTO encounter_search(encounter_search, rowid, current_boss, players, note) VALUES('delete', ?1, 'wrong', 'wrong', NULL)",
+            [kept]).unwrap();
+        connection.execute_batch("PRAGMA foreign_keys = ON").unwrap();
+
+        let report = repository.check_integrity().unwrap();
+        assert!(report.integrity_errors.is_empty());
+        assert!(!report.search_index_ok);
+        assert!(!report.foreign_key_violations.is_empty());
+        assert_eq!(report.orphaned_entities, vec![without_encounter]);
+        assert_eq!(report.orphaned_previews, vec![without_encounter]);
+        assert_eq!(report.encounters_without_preview, vec![without_preview]);
+
+        let transaction = connection.transaction().unwrap();
+        let repaired = repository.repair(&transaction).unwrap();
+        transaction.commit().unwrap();
+
+        assert_eq!(repaired.deleted_orphans, vec![without_encounter]);
+        assert_eq!(repaired.deleted_foreign_key_violations, 1);
+        assert_eq!(repaired.regenerated_previews, vec![without_preview]);
+
+        let report = repository.check_integrity().unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert!(repository.load_encounter(kept).is_ok());
+        assert!(repository.load_encounter(without_preview).is_ok());
+    }
+}
@@ -0,0 +1,193 @@
+use rusqlite::params_from_iter;
+
+use crate::{error::*, models::{BossStats, EncounterFilter}};
+
+use super::{load_encounters_preview::PreviewQuery, SqliteRepository};
+
+impl SqliteRepository {
+
+    /// Same search and filter as the preview listing, the sort is ignored.
+    ///
+    /// SQLite has no median aggregate, so only that one is computed here from the dps column.
+    pub(crate) fn load_boss_stats_inner(
+        &self,
+        search: String,
+        filter: EncounterFilter,
+    ) -> Result<Vec<BossStats>> {
+        let preview_query = PreviewQuery::new(search, filter)?;
+        let connection = self.pool.get()?;
+
+        let query = format!(
+            "SELECT
+            IFNULL(e.current_boss, ''),
+            IFNULL(e.difficulty, ''),
+            COUNT(*),
+            COUNT(CASE WHEN e.cleared = 1 THEN 1 END),
+            CAST(AVG(CASE WHEN e.cleared = 1 THEN e.duration END) AS INTEGER),
+            MIN(CASE WHEN e.cleared = 1 THEN e.duration END),
+            MAX(CASE WHEN e.my_dps > 0 THEN e.my_dps END)
+        {}
+        GROUP BY 1, 2
+        ORDER BY 1, 2",
+            preview_query.from,
+        );
+
+        let mut statement = connection.prepare_cached(&query)?;
+
+        let mut stats: Vec<BossStats> = statement
+            .query_map(params_from_iter(&preview_query.params), |row| {
+                let attempts: i64 = row.get(2)?;
+                let clears: i64 = row.get(3)?;
+
+                Ok(BossStats {
+                    boss: row.get(0)?,
+                    difficulty: row.get(1)?,
+                    attempts,
+                    clears,
+                    clear_rate: clears as f64 / attempts as f64,
+                    average_clear_duration: row.get(4)?,
+                    best_clear_duration: row.get(5)?,
+                    best_dps: row.get(6)?,
+                    median_dps: None,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        let query = format!(
+            "SELECT
+            IFNULL(e.current_boss, ''),
+            IFNULL(e.difficulty, ''),
+            e.my_dps
+        {} AND e.my_dps > 0
+        ORDER BY 1, 2, 3",
+            preview_query.from,
+        );
+
+        let mut statement = connection.prepare_cached(&query)?;
+        let mut rows = statement.query(params_from_iter(&preview_query.params))?;
+
+        let mut groups = stats.iter_mut().peekable();
+        let mut dps: Vec<i64> = vec![];
+
+        while let Some(row) = rows.next()? {
+            let boss: String = row.get(0)?;
+            let difficulty: String = row.get(1)?;
+
+            // both queries are ordered by boss and difficulty, every dps group has a stats row
+            while let Some(current) = groups.next_if(|current| current.boss != boss || current.difficulty != difficulty) {
+                current.median_dps = median(&dps);
+                dps.clear();
+            }
+
+            dps.push(row.get(2)?);
+        }
+
+        if let Some(current) = groups.next() {
+            current.median_dps = median(&dps);
+        }
+
+        Ok(stats)
+    }
+}
+
+/// `values` must be sorted.
+fn median(values: &[i64]) -> Option<i64> {
+    if values.is_empty() {
+        return None;
+    }
+
+    let middle = values.len() / 2;
+
+    Some(if values.len() % 2 == 0 {
+        (values[middle - 1] + values[middle]) / 2
+    } else {
+        values[middle]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use lost_metrics_core::models::SearchFilter;
+
+    use crate::{models::{BossStats, EncounterFilter}, repository::{test_utils::*, Repository}};
+
+    fn attempt(boss: &'static str, duration: i64, cleared: bool, dps: i64) -> TestEncounter {
+        TestEncounter {
+            boss,
+            duration,
+            cleared,
+            players: vec![TestPlayer { name: "test", class_id: 204, character_id: 1, gear_score: 1700.0, dps }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn should_aggregate_per_boss_and_difficulty() {
+        let repository = setup();
+        attempt("Narok the Butcher", 600_000, false, 100).insert(&repository);
+        attempt("Narok the Butcher", 500_000, true, 300).insert(&repository);
+        attempt("Narok the Butcher", 400_000, true, 200).insert(&repository);
+        attempt("Narok the Butcher", 450_000, false, 400).insert(&repository);
+        attempt("Thaemine", 900_000, false, 50).insert(&repository);
+
+        let stats = repository.load_boss_stats("".into(), SearchFilter::default().into()).unwrap();
+
+        assert_eq!(stats, vec![
+            BossStats {
+                boss: "Narok the Butcher".into(),
+                difficulty: "Hard".into(),
+                attempts: 4,
+                clears: 2,
+                clear_rate: 0.5,
+                average_clear_duration: Some(450_000),
+                best_clear_duration: Some(400_000),
+                best_dps: Some(400),
+                median_dps: Some(250),
+            },
+            BossStats {
+                boss: "Thaemine".into(),
+                difficulty: "Hard".into(),
+                attempts: 1,
+                clears: 0,
+                clear_rate: 0.0,
+                average_clear_duration: None,
+                best_clear_duration: None,
+                best_dps: Some(50),
+                median_dps: Some(50),
+            },
+        ]);
+    }
+
+    #[test]
+    fn should_compute_median_per_group() {
+        let repository = setup();
+        attempt("Brelshaza", 600_000, false, 100).insert(&repository);
+        attempt("Brelshaza", 600_000, false, 300).insert(&repository);
+        attempt("Echidna", 600_000, false, 0).insert(&repository);
+        attempt("Thaemine", 600_000, false, 50).insert(&repository);
+
+        let stats = repository.load_boss_stats("".into(), SearchFilter::default().into()).unwrap();
+        let medians: Vec<_> = stats.iter().map(|stats| stats.median_dps).collect();
+
+        assert_eq!(medians, vec![Some(200), None, Some(50)]);
+        assert_eq!(stats[1].best_dps, None);
+    }
+
+    #[test]
+    fn should_respect_date_and_region_filter() {
+        let repository = setup();
+        TestEncounter { fight_start: 1_000, region: Some("EUC"), ..Default::default() }.insert(&repository);
+        TestEncounter { fight_start: 5_000, region: Some("EUC"), ..Default::default() }.insert(&repository);
+        TestEncounter { fight_start: 5_000, region: Some("NAE"), ..Default::default() }.insert(&repository);
+
+        let filter = EncounterFilter {
+            fight_start_from: Some(2_000),
+            region: Some("EUC".into()),
+            ..Default::default()
+        };
+        let stats = repository.load_boss_stats("".into(), filter).unwrap();
+
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].attempts, 1);
+    }
+}
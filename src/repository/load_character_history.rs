@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+
+use rusqlite::params;
+
+use crate::{error::*, models::{BossDpsSeries, CharacterHistory, DpsPoint, GearScorePoint}};
+
+use super::{queries::SELECT_CHARACTER_HISTORY, SqliteRepository};
+
+impl SqliteRepository {
+
+    /// Matches the character by id, entities saved before character ids were recorded are matched by name.
+    /// With a `character_id` of 0 every entity with the name matches.
+    pub(crate) fn load_character_history_inner(
+        &self,
+        character_id: u64,
+        name: &str,
+    ) -> Result<CharacterHistory> {
+        let connection = self.pool.get()?;
+        let mut statement = connection.prepare_cached(SELECT_CHARACTER_HISTORY)?;
+        let mut rows = statement.query(params![character_id as i64, name])?;
+
+        let mut dps: BTreeMap<(String, String), Vec<DpsPoint>> = BTreeMap::new();
+        let mut gear_score: Vec<GearScorePoint> = vec![];
+
+        while let Some(row) = rows.next()? {
+            let encounter_id: i64 = row.get(0)?;
+            let fight_start: i64 = row.get(1)?;
+            let current_gear_score: Option<f32> = row.get(6)?;
+
+            dps.entry((row.get(2)?, row.get(3)?))
+                .or_default()
+                .push(DpsPoint {
+                    encounter_id,
+                    fight_start,
+                    class_id: row.get(4)?,
+                    spec: row.get(5)?,
+                    dps: row.get::<_, Option<i64>>(7)?.unwrap_or_default(),
+                });
+
+            if let Some(current_gear_score) = current_gear_score {
+                if gear_score.last().is_none_or(|point| point.gear_score != current_gear_score) {
+                    gear_score.push(GearScorePoint {
+                        encounter_id,
+                        fight_start,
+                        gear_score: current_gear_score,
+                    });
+                }
+            }
+        }
+
+        let dps = dps
+            .into_iter()
+            .map(|((boss, difficulty), points)| BossDpsSeries {
+                boss,
+                difficulty,
+                points,
+            })
+            .collect();
+
+        Ok(CharacterHistory {
+            dps,
+            gear_score,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repository::{test_utils::*, Repository};
+
+    fn attempt(fight_start: i64, boss: &'static str, player: TestPlayer) -> TestEncounter {
+        TestEncounter {
+            fight_start,
+            boss,
+            players: vec![player],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn should_group_dps_and_track_gear_score() {
+        let repository = setup();
+        let player = |character_id, gear_score, dps| TestPlayer { name: "test", class_id: 204, character_id, gear_score, dps };
+
+        let first = attempt(1_000, "Narok the Butcher", player(0, 1680.0, 100)).insert(&repository);
+        let second = attempt(2_000, "Thaemine", player(7, 1680.0, 150)).insert(&repository);
+        let third = attempt(3_000, "Narok the Butcher", player(7, 1700.0, 200)).insert(&repository);
+        attempt(4_000, "Narok the Butcher", player(8, 1720.0, 900)).insert(&repository);
+
+        let history = repository.load_character_history(7, "test").unwrap();
+
+        let narok: Vec<_> = history.dps[0].points.iter().map(|point| (point.encounter_id, point.dps)).collect();
+        assert_eq!(history.dps[0].boss, "Narok the Butcher");
+        assert_eq!(narok, vec![(first, 100), (third, 200)]);
+        assert_eq!(history.dps[1].boss, "Thaemine");
+        assert_eq!(history.dps[1].points[0].encounter_id, second);
+
+        let gear_score: Vec<_> = history.gear_score.iter().map(|point| (point.encounter_id, point.gear_score)).collect();
+        assert_eq!(gear_score, vec![(first, 1680.0), (third, 1700.0)]);
+
+        let by_name = repository.load_character_history(0, "test").unwrap();
+        assert_eq!(by_name.dps[0].points.len(), 3);
+    }
+}
@@ -0,0 +1,250 @@
+use hashbrown::{HashMap, HashSet};
+use lost_metrics_core::models::*;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::{error::*, utils::{get_compressed_json, get_json}};
+
+use super::{queries::{SELECT_ENCOUNTER, SELECT_ENTITIES}, SqliteRepository};
+
+impl SqliteRepository {
+
+    pub(crate) fn load_encounter_inner(&self, id: i64) -> Result<Encounter> {
+        let connection = self.pool.get()?;
+
+        let mut encounter = connection
+            .query_row(SELECT_ENCOUNTER, params![id], Self::map_to_encounter)
+            .optional()?
+            .ok_or(StoreError::NotFound(id))?;
+
+        let entities = Self::load_entities(&connection, id)?;
+
+        encounter.current_boss = entities.get(&encounter.current_boss_name).cloned();
+        encounter.encounter_damage_stats.most_damage_taken_entity = entities
+            .values()
+            .filter(|entity| entity.entity_type == EntityType::Player)
+            // ties go to the first name, entities come back in no particular order
+            .max_by(|a, b| a.damage_stats.damage_taken
+                .cmp(&b.damage_stats.damage_taken)
+                .then_with(|| b.name.cmp(&a.name)))
+            .map(|entity| MostDamageTakenEntity {
+                name: entity.name.clone(),
+                damage_taken: entity.damage_stats.damage_taken,
+            })
+            .unwrap_or(MostDamageTakenEntity {
+                name: String::new(),
+                damage_taken: 0,
+            });
+        encounter.entities = entities;
+
+        Ok(encounter)
+    }
+
+    fn load_entities(connection: &Connection, encounter_id: i64) -> Result<HashMap<String, EncounterEntity>> {
+        let mut statement = connection.prepare_cached(SELECT_ENTITIES)?;
+
+        let entities = statement
+            .query_map(params![encounter_id], Self::map_to_entity)?
+            .map(|entity| entity.map(|entity| (entity.name.clone(), entity)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(entities)
+    }
+
+    fn map_to_encounter(row: &Row) -> rusqlite::Result<Encounter> {
+        let misc: Option<EncounterMisc> = get_json(row, 19)?;
+        let difficulty: Option<String> = row.get(3)?;
+
+        Ok(Encounter {
+            last_combat_packet: row.get(8)?,
+            fight_start: row.get(0)?,
+            local_player: row.get(4)?,
+            entities: HashMap::new(),
+            current_boss_name: row.get(1)?,
+            current_boss: None,
+            encounter_damage_stats: EncounterDamageStats {
+                total_damage_dealt: row.get(9)?,
+                top_damage_dealt: row.get(10)?,
+                total_damage_taken: row.get(11)?,
+                top_damage_taken: row.get(12)?,
+                dps: row.get(13)?,
+                most_damage_taken_entity: MostDamageTakenEntity {
+                    name: String::new(),
+                    damage_taken: 0,
+                },
+                buffs: get_compressed_json(row, 14)?,
+                debuffs: get_compressed_json(row, 15)?,
+                total_shielding: row.get::<_, Option<u64>>(16)?.unwrap_or_default(),
+                total_effective_shielding: row.get::<_, Option<u64>>(17)?.unwrap_or_default(),
+                applied_shield_buffs: get_compressed_json(row, 18)?,
+                unknown_buffs: HashSet::new(),
+                max_stagger: 0,
+                stagger_start: 0,
+                misc,
+                boss_hp_log: get_compressed_json(row, 20)?,
+                stagger_stats: get_json(row, 21)?,
+            },
+            duration: row.get(2)?,
+            difficulty,
+            favorite: row.get(5)?,
+            cleared: row.get::<_, Option<bool>>(6)?.unwrap_or_default(),
+            boss_only_damage: row.get(7)?,
+            sync: None,
+        })
+    }
+
+    fn map_to_entity(row: &Row) -> rusqlite::Result<EncounterEntity> {
+        let entity_type: String = row.get(3)?;
+
+        Ok(EncounterEntity {
+            id: 0,
+            character_id: row.get::<_, Option<u64>>(1)?.unwrap_or_default(),
+            npc_id: row.get::<_, Option<u32>>(2)?.unwrap_or_default(),
+            name: row.get(0)?,
+            entity_type: entity_type.parse().unwrap_or_default(),
+            class_id: row.get::<_, Option<u32>>(4)?.unwrap_or_default(),
+            class: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+            gear_score: row.get::<_, Option<f32>>(6)?.unwrap_or_default(),
+            current_hp: row.get::<_, Option<i64>>(7)?.unwrap_or_default(),
+            max_hp: row.get::<_, Option<i64>>(8)?.unwrap_or_default(),
+            current_shield: 0,
+            is_dead: row.get::<_, Option<bool>>(9)?.unwrap_or_default(),
+            skills: get_compressed_json(row, 10)?,
+            damage_stats: get_compressed_json(row, 11)?,
+            skill_stats: get_json(row, 12)?,
+            engraving_data: get_json(row, 13)?,
+            gear_hash: row.get(14)?,
+            ark_passive_active: row.get(15)?,
+            ark_passive_data: get_json(row, 17)?,
+            spec: row.get(16)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use hashbrown::HashMap;
+    use lost_metrics_core::models::{DamageStats, EntityType, SkillStats};
+    use lost_metrics_misc::compress_json;
+    use serde_json::json;
+
+    use crate::{connection_pool, error::StoreError, migration_runner::MigrationRunner, models::{EncounterDb, EncounterPreviewDb, EntityDb, PreviewPlayer}, repository::{Repository, SqliteRepository}};
+
+    #[test]
+    fn should_load_encounter_with_entities() {
+        let pool = connection_pool::in_memory().unwrap();
+        let repository = SqliteRepository::new(pool.clone());
+        let migration_runner = MigrationRunner::new(pool);
+
+        migration_runner.run().unwrap();
+
+        let fight_start = Utc::now();
+        let last_combat_packet = (fight_start + Duration::minutes(10)).timestamp_millis();
+        let fight_start = fight_start.timestamp_millis();
+
+        let encounter = EncounterDb {
+            last_combat_packet,
+            total_damage_dealt: 1000,
+            top_damage_dealt: 1000,
+            total_damage_taken: 10,
+            top_damage_taken: 10,
+            dps: 100,
+            compressed_buffs: compress_json(&json!({})),
+            compressed_debuffs: compress_json(&json!({})),
+            total_shielding: 0,
+            total_effective_shielding: 0,
+            compressed_shields: compress_json(&json!({})),
+            misc_json: json!({ "raidClear": true }),
+            db_version: 5,
+            compressed_boss_hp: compress_json(&json!({})),
+            stagger_stats_json: json!(null),
+        };
+
+        let encounter_preview = EncounterPreviewDb {
+            fight_start,
+            current_boss_name: "Narok the Butcher",
+            duration: last_combat_packet - fight_start,
+            players: vec![PreviewPlayer {
+                name: "test".into(),
+                class_id: 204,
+                spec: None,
+                gear_score: 1700.0,
+                dps: 100,
+            }],
+            raid_difficulty: "Hard",
+            local_player: "test",
+            local_player_dps: 100,
+            raid_clear: Some(true),
+            boss_only_damage: true,
+        };
+
+        let skills = HashMap::new();
+        let damage_stats = DamageStats {
+            damage_dealt: 1000,
+            damage_taken: 10,
+            dps: 100,
+            ..Default::default()
+        };
+        let entity = EntityDb {
+            id: 1,
+            character_id: 1,
+            npc_id: 0,
+            name: "test",
+            entity_type: EntityType::Player.to_string(),
+            class_id: 204,
+            class: "Bard",
+            gear_score: 1700.0,
+            current_hp: 100,
+            max_hp: 100,
+            current_shield: 0,
+            is_dead: false,
+            skills: &skills,
+            damage_stats: damage_stats.clone(),
+            skill_stats: SkillStats::default(),
+            engraving_data: None,
+            gear_hash: None,
+            ark_passive_active: Some(true),
+            ark_passive_data: None,
+            spec: None,
+            compressed_damage_stats: compress_json(&damage_stats),
+            compressed_skills: compress_json(&skills),
+            skill_stats_json: json!(SkillStats::default()),
+            engraving_data_json: json!(null),
+            ark_passive_data_json: json!(null),
+        };
+
+        let encounter_id = {
+            let connection = repository.get_connection().unwrap();
+            let encounter_id = repository.insert_encounter(&connection, encounter).unwrap();
+            repository.insert_entities(&connection, encounter_id, &[entity]).unwrap();
+            repository.insert_encounter_preview(&connection, encounter_id, encounter_preview).unwrap();
+            encounter_id
+        };
+
+        let encounter = repository.load_encounter(encounter_id).unwrap();
+
+        assert_eq!(encounter.fight_start, fight_start);
+        assert_eq!(encounter.current_boss_name, "Narok the Butcher");
+        assert_eq!(encounter.difficulty.as_deref(), Some("Hard"));
+        assert!(encounter.cleared);
+        assert!(encounter.encounter_damage_stats.misc.unwrap().raid_clear.unwrap());
+
+        let entity = encounter.entities.get("test").unwrap();
+        assert_eq!(entity.entity_type, EntityType::Player);
+        assert_eq!(entity.class_id, 204);
+        assert_eq!(entity.damage_stats.dps, 100);
+        assert_eq!(encounter.encounter_damage_stats.most_damage_taken_entity.name, "test");
+    }
+
+    #[test]
+    fn should_fail_to_load_missing_encounter() {
+        let pool = connection_pool::in_memory().unwrap();
+        let repository = SqliteRepository::new(pool.clone());
+        let migration_runner = MigrationRunner::new(pool);
+
+        migration_runner.run().unwrap();
+
+        let result = repository.load_encounter(1);
+        assert!(matches!(result, Err(StoreError::NotFound(1))));
+    }
+}
@@ -1,21 +1,47 @@
 use lost_metrics_core::models::*;
-use rusqlite::{params_from_iter, Row};
-use anyhow::*;
+use rusqlite::{params_from_iter, types::{Value, ValueRef}, Row};
 
-use super::SqliteRepository;
+use crate::{error::*, models::{EncounterFilter, EncounterPreviewItem, EncounterPreviewOverview, Participant, ParticipantMatch, PreviewPlayer, SortColumn}, utils::get_json};
 
-impl SqliteRepository {
+use super::SqliteRepository;
 
-    pub(crate) fn load_encounters_preview_inner(
-        &self,
-        page: i32,
-        page_size: i32,
-        search: String,
-        filter: SearchFilter,
-    ) -> Result<EncountersOverview> {
-        let connection = self.pool.get()?;
+pub(crate) const PREVIEW_COLUMNS: &str = "
+        e.id,
+        e.fight_start,
+        e.current_boss,
+        e.duration,
+        e.difficulty,
+        e.favorite,
+        e.cleared,
+        e.local_player,
+        e.my_dps,
+        e.player_data,
+        e.players";
+
+/// `FROM` and `WHERE` clauses shared by every preview listing, along with their parameters.
+pub(crate) struct PreviewQuery {
+    pub from: String,
+    pub params: Vec<Value>,
+    pub sort: SortColumn,
+    pub ascending: bool,
+}
 
-        let mut params = vec![];
+impl PreviewQuery {
+    pub fn new(search: String, filter: EncounterFilter) -> Result<Self> {
+        let EncounterFilter {
+            base: filter,
+            fight_start_from,
+            fight_start_to,
+            max_duration,
+            mut difficulties,
+            participants,
+            participant_match,
+            tags,
+            region,
+        } = filter;
+        let sort: SortColumn = filter.sort.parse()?;
+
+        let mut params: Vec<Value> = vec![];
 
         let join_clause = if search.len() > 2 {
             let escaped_search = search
@@ -23,18 +49,18 @@ impl SqliteRepository {
                 .map(|word| format!("\"{}\"", word.replace("\"", "")))
                 .collect::<Vec<_>>()
                 .join(" ");
-            params.push(escaped_search);
+            params.push(escaped_search.into());
             "JOIN encounter_search(?) ON encounter_search.rowid = e.id"
         } else {
             ""
         };
 
-        params.push((filter.min_duration * 1000).to_string());
+        params.push((filter.min_duration as i64 * 1000).into());
 
         let boss_filter = if !filter.bosses.is_empty() {
             let mut placeholders = "?,".repeat(filter.bosses.len());
             placeholders.pop(); // remove trailing comma
-            params.extend(filter.bosses);
+            params.extend(filter.bosses.into_iter().map(Value::from));
             format!("AND e.current_boss IN ({})", placeholders)
         } else {
             "".to_string()
@@ -58,79 +84,170 @@ impl SqliteRepository {
             ""
         };
 
-        let difficulty_filter = if !filter.difficulty.is_empty() {
-            params.push(filter.difficulty);
-            "AND difficulty = ?"
+        if !filter.difficulty.is_empty() {
+            difficulties.push(filter.difficulty);
+        }
+
+        let difficulty_filter = if !difficulties.is_empty() {
+            let mut placeholders = "?,".repeat(difficulties.len());
+            placeholders.pop();
+            params.extend(difficulties.into_iter().map(Value::from));
+            format!("AND difficulty IN ({})", placeholders)
+        } else {
+            "".to_string()
+        };
+
+        let max_duration_filter = if let Some(max_duration) = max_duration {
+            params.push((max_duration * 1000).into());
+            "AND e.duration <= ?"
         } else {
             ""
         };
 
-        let order = if filter.order == 1 { "ASC" } else { "DESC" };
-        let sort = format!("e.{}", filter.sort);
+        let fight_start_from_filter = if let Some(fight_start_from) = fight_start_from {
+            params.push(fight_start_from.into());
+            "AND e.fight_start >= ?"
+        } else {
+            ""
+        };
 
-        let count_params = params.clone();
+        let fight_start_to_filter = if let Some(fight_start_to) = fight_start_to {
+            params.push(fight_start_to.into());
+            "AND e.fight_start <= ?"
+        } else {
+            ""
+        };
 
-        let query = format!(
-            "SELECT
-        e.id,
-        e.fight_start,
-        e.current_boss,
-        e.duration,
-        e.difficulty,
-        e.favorite,
-        e.cleared,
-        e.local_player,
-        e.my_dps,
-        e.players
-        FROM encounter_preview e {}
+        let participant_filter = Self::participant_filter(&participants, participant_match, &mut params);
+
+        let tag_filter = if !tags.is_empty() {
+            let mut placeholders = "?,".repeat(tags.len());
+            placeholders.pop();
+            params.extend(tags.into_iter().map(|tag| Value::from(tag.trim().to_string())));
+            format!("AND e.id IN (SELECT encounter_id FROM encounter_tag WHERE tag IN ({}))", placeholders)
+        } else {
+            "".to_string()
+        };
+
+        let region_filter = if let Some(region) = region {
+            params.push(region.into());
+            "AND e.id IN (SELECT id FROM encounter WHERE json_extract(misc, '$.region') = ?)"
+        } else {
+            ""
+        };
+
+        let from = format!(
+            "FROM encounter_preview e {}
         WHERE e.duration > ? {}
         {} {} {} {}
-        ORDER BY {} {}
-        LIMIT ?
-        OFFSET ?",
+        {} {} {}
+        {} {} {}",
             join_clause,
             boss_filter,
             raid_clear_filter,
             favorite_filter,
             difficulty_filter,
             boss_only_damage_filter,
-            sort,
-            order
+            max_duration_filter,
+            fight_start_from_filter,
+            fight_start_to_filter,
+            participant_filter,
+            tag_filter,
+            region_filter
         );
 
-        let mut statement = connection.prepare_cached(&query).unwrap();
+        Ok(Self {
+            from,
+            params,
+            sort,
+            ascending: filter.order == 1,
+        })
+    }
 
-        let offset = (page - 1) * page_size;
+    fn participant_filter(participants: &[Participant], participant_match: ParticipantMatch, params: &mut Vec<Value>) -> String {
+        let conditions: Vec<_> = participants
+            .iter()
+            .filter_map(|participant| {
+                let mut conditions = vec![];
 
-        params.push(page_size.to_string());
-        params.push(offset.to_string());
+                if let Some(name) = &participant.name {
+                    params.push(name.clone().into());
+                    conditions.push("name = ?");
+                }
 
-        let encounter_iter = statement
-            .query_map(params_from_iter(params), |row| Self::map_to_row(row))
-            .expect("could not query encounters");
+                if let Some(class_id) = participant.class_id {
+                    params.push(class_id.into());
+                    conditions.push("class_id = ?");
+                }
 
-        let encounters: Vec<EncounterPreview> = encounter_iter.collect::<Result<_, _>>().unwrap();
+                if let Some(character_id) = participant.character_id {
+                    params.push((character_id as i64).into());
+                    conditions.push("character_id = ?");
+                }
+
+                (!conditions.is_empty()).then(|| format!(
+                    "e.id IN (SELECT encounter_id FROM entity WHERE entity_type = 'PLAYER' AND {})",
+                    conditions.join(" AND ")))
+            })
+            .collect();
+
+        if conditions.is_empty() {
+            return "".to_string();
+        }
+
+        let separator = match participant_match {
+            ParticipantMatch::Any => " OR ",
+            ParticipantMatch::All => " AND ",
+        };
+
+        format!("AND ({})", conditions.join(separator))
+    }
+
+    /// id breaks ties, so rows with equal values keep a stable order across pages.
+    pub fn order_by(&self, ascending: bool) -> String {
+        let order = if ascending { "ASC" } else { "DESC" };
+        format!("{expression} {order}, e.id {order}", expression = self.sort.expression())
+    }
+}
+
+impl SqliteRepository {
+
+    pub(crate) fn load_encounters_preview_inner(
+        &self,
+        page: i32,
+        page_size: i32,
+        search: String,
+        filter: EncounterFilter,
+    ) -> Result<EncounterPreviewOverview> {
+        let preview_query = PreviewQuery::new(search, filter)?;
+        let connection = self.pool.get()?;
 
         let query = format!(
-            "
-            SELECT COUNT(*)
-            FROM encounter_preview e {}
-            WHERE duration > ? {}
-            {} {} {} {}
-            ",
-            join_clause,
-            boss_filter,
-            raid_clear_filter,
-            favorite_filter,
-            difficulty_filter,
-            boss_only_damage_filter
+            "SELECT {}
+        {}
+        ORDER BY {}
+        LIMIT ?
+        OFFSET ?",
+            PREVIEW_COLUMNS,
+            preview_query.from,
+            preview_query.order_by(preview_query.ascending)
         );
 
-        let count: i32 = connection
-            .query_row_and_then(&query, params_from_iter(count_params), |row| row.get(0))
-            .expect("could not get encounter count");
+        let mut statement = connection.prepare_cached(&query)?;
+
+        let offset = (page - 1) * page_size;
+
+        let mut params = preview_query.params.clone();
+        params.push(page_size.into());
+        params.push(offset.into());
+
+        let encounters = statement
+            .query_map(params_from_iter(params), Self::map_to_row)?
+            .collect::<Result<_, _>>()?;
 
-        let result = EncountersOverview {
+        let count = Self::count_encounters_preview(&connection, &preview_query)?;
+
+        let result = EncounterPreviewOverview {
             encounters,
             total_encounters: count,
         };
@@ -138,21 +255,27 @@ impl SqliteRepository {
         Ok(result)
     }
 
-    fn map_to_row(row: &Row) -> rusqlite::Result<EncounterPreview> {
-        let classes: String = row.get(9).unwrap_or_default();
+    pub(crate) fn count_encounters_preview(connection: &rusqlite::Connection, preview_query: &PreviewQuery) -> Result<i32> {
+        let query = format!("SELECT COUNT(*) {}", preview_query.from);
 
-        let (classes, names) = classes
-            .split(',')
-            .map(|s| {
-                let info: Vec<&str> = s.split(':').collect();
-                if info.len() != 2 {
-                    return (101, "Unknown".to_string());
-                }
-                (info[0].parse::<i32>().unwrap_or(101), info[1].to_string())
-            })
+        let count = connection
+            .query_row(&query, params_from_iter(&preview_query.params), |row| row.get(0))?;
+
+        Ok(count)
+    }
+
+    pub(crate) fn map_to_row(row: &Row) -> rusqlite::Result<EncounterPreviewItem> {
+        let players: Vec<PreviewPlayer> = match row.get_ref(9)? {
+            ValueRef::Null => Self::parse_legacy_players(row.get::<_, Option<String>>(10)?.unwrap_or_default()),
+            _ => get_json(row, 9)?,
+        };
+
+        let (classes, names) = players
+            .iter()
+            .map(|player| (player.class_id as i32, player.name.clone()))
             .unzip();
 
-        std::result::Result::Ok(EncounterPreview {
+        let preview = EncounterPreview {
             id: row.get(0)?,
             fight_start: row.get(1)?,
             boss_name: row.get(2)?,
@@ -161,27 +284,66 @@ impl SqliteRepository {
             names,
             difficulty: row.get(4)?,
             favorite: row.get(5)?,
-            cleared: row.get(6)?,
+            cleared: row.get::<_, Option<bool>>(6)?.unwrap_or_default(),
             local_player: row.get(7)?,
             my_dps: row.get(8).unwrap_or(0),
+        };
+
+        Ok(EncounterPreviewItem {
+            preview,
+            players,
         })
     }
+
+    /// Rows the `player_data` backfill missed still have the `class_id:name` list.
+    fn parse_legacy_players(players: String) -> Vec<PreviewPlayer> {
+        players
+            .split(',')
+            .filter_map(|player| player.split_once(':'))
+            .map(|(class_id, name)| PreviewPlayer {
+                name: name.to_string(),
+                class_id: class_id.parse().unwrap_or_default(),
+                spec: None,
+                gear_score: 0.0,
+                dps: 0,
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use chrono::{Duration, Utc};
-    use hashbrown::{HashMap, HashSet};
-    use lost_metrics_core::models::{DamageStats, Encounter, EncounterDamageStats, EncounterEntity, EntityType, MostDamageTakenEntity, SkillStats};
     use serde_json::json;
 
-    use crate::{connection_pool, migration_runner::{self, MigrationRunner}, models::{EncounterDb, EncounterPreviewDb}, repository::{Repository, SqliteRepository}};
+    use crate::{connection_pool, migration_runner::MigrationRunner, models::{EncounterDb, EncounterFilter, EncounterPreviewDb, Participant, ParticipantMatch}, repository::{test_utils::*, Repository, SqliteRepository}};
 
     use super::*;
 
+    #[test]
+    fn should_deserialize_filter_with_search_filter_inline() {
+        // what the frontend sends, a search filter with the extra fields next to its own
+        let mut value = serde_json::to_value(SearchFilter { min_duration: 30, ..Default::default() }).unwrap();
+        value.as_object_mut().unwrap().extend(json!({
+            "fightStartFrom": 1000,
+            "participants": [{ "name": "alice" }],
+            "participantMatch": "all",
+            "tags": ["prog"]
+        }).as_object().unwrap().clone());
+
+        let filter: EncounterFilter = serde_json::from_value(value).unwrap();
+
+        assert_eq!(filter.base.min_duration, 30);
+        assert_eq!(filter.fight_start_from, Some(1000));
+        assert_eq!(filter.participants, vec![Participant { name: Some("alice".into()), ..Default::default() }]);
+        assert_eq!(filter.participant_match, ParticipantMatch::All);
+        assert_eq!(filter.tags, vec!["prog".to_string()]);
+        assert!(filter.difficulties.is_empty());
+    }
+
     #[test]
     fn should_return_encounter() {
-        let pool = connection_pool::in_memory();
+        let pool = connection_pool::in_memory().unwrap();
         let repository = SqliteRepository::new(pool.clone());
         let migration_runner = MigrationRunner::new(pool);
         
@@ -210,10 +372,10 @@ mod tests {
 
         let encounter_preview = EncounterPreviewDb {
             fight_start: 10000,
-            current_boss_name: "Narok the Butcher".into(),
+            current_boss_name: "Narok the Butcher",
             duration: 100,
-            preview_players: "test",
-            raid_difficulty: "Hard".into(),
+            players: vec![],
+            raid_difficulty: "Hard",
             local_player: "test",
             local_player_dps: 10,
             raid_clear: Some(true),
@@ -228,7 +390,181 @@ mod tests {
             ..Default::default()
         };
 
-        let result = repository.load_encounters_preview_inner(0, 10, "".into(), filter).unwrap();
+        let result = repository.load_encounters_preview_inner(0, 10, "".into(), filter.into()).unwrap();
         assert_eq!(result.encounters.len(), 1);
     }
+
+    #[test]
+    fn should_sort_by_column_with_id_tie_breaker() {
+        let repository = setup();
+
+        let low_dps = TestEncounter {
+            players: vec![TestPlayer { name: "test", class_id: 204, character_id: 1, gear_score: 1700.0, dps: 50 }],
+            ..Default::default()
+        }.insert(&repository);
+        let first_high_dps = TestEncounter::default().insert(&repository);
+        let second_high_dps = TestEncounter::default().insert(&repository);
+
+        let filter = SearchFilter {
+            sort: "my_dps".into(),
+            ..Default::default()
+        };
+
+        let result = repository.load_encounters_preview(1, 10, "".into(), filter.into()).unwrap();
+        let ids: Vec<_> = result.encounters.iter().map(|encounter| encounter.preview.id as i64).collect();
+        assert_eq!(ids, vec![second_high_dps, first_high_dps, low_dps]);
+    }
+
+    #[test]
+    fn should_return_players_with_offset_pages() {
+        let repository = setup();
+        let id = TestEncounter {
+            players: vec![
+                TestPlayer { name: "alice", class_id: 204, character_id: 1, gear_score: 1710.0, dps: 300 },
+                TestPlayer { name: "bob", class_id: 102, character_id: 2, gear_score: 1680.0, dps: 200 },
+            ],
+            ..Default::default()
+        }.insert(&repository);
+
+        let result = repository.load_encounters_preview(1, 10, "".into(), SearchFilter::default().into()).unwrap();
+        assert_eq!(result.encounters[0].players[0].dps, 300);
+
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["totalEncounters"], 1);
+        assert_eq!(json["encounters"][0]["players"][0]["gearScore"], 1710.0);
+
+        // rows the backfill missed fall back to the legacy list
+        repository.get_connection().unwrap()
+            .execute("UPDATE encounter_preview SET player_data = NULL WHERE id = ?1", [id])
+            .unwrap();
+
+        let result = repository.load_encounters_preview(1, 10, "".into(), SearchFilter::default().into()).unwrap();
+        let item = &result.encounters[0];
+        assert_eq!(item.preview.names, vec!["alice".to_string(), "bob".to_string()]);
+        assert_eq!(item.preview.classes, vec![204, 102]);
+        assert_eq!(item.players[1].dps, 0);
+    }
+
+    #[test]
+    fn should_reject_unknown_sort_column() {
+        let repository = setup();
+
+        let filter = SearchFilter {
+            sort: "fight_start; DROP TABLE encounter".into(),
+            ..Default::default()
+        };
+
+        let result = repository.load_encounters_preview(1, 10, "".into(), filter.into());
+        assert!(matches!(result, Err(StoreError::InvalidFilter(_))));
+    }
+
+    #[test]
+    fn should_filter_by_date_range_duration_and_difficulties() {
+        let repository = setup();
+
+        let normal = TestEncounter {
+            fight_start: 1_000_000,
+            difficulty: "Normal",
+            ..Default::default()
+        }.insert(&repository);
+        let hard = TestEncounter {
+            fight_start: 2_000_000,
+            difficulty: "Hard",
+            ..Default::default()
+        }.insert(&repository);
+        TestEncounter {
+            fight_start: 2_500_000,
+            difficulty: "Inferno",
+            ..Default::default()
+        }.insert(&repository);
+        TestEncounter {
+            fight_start: 3_000_000,
+            duration: 1_200_000,
+            ..Default::default()
+        }.insert(&repository);
+        TestEncounter {
+            fight_start: 9_000_000,
+            ..Default::default()
+        }.insert(&repository);
+
+        let filter = EncounterFilter {
+            base: SearchFilter {
+                sort: "fight_start".into(),
+                order: 1,
+                ..Default::default()
+            },
+            fight_start_from: Some(1_000_000),
+            fight_start_to: Some(3_000_000),
+            max_duration: Some(900),
+            difficulties: vec!["Normal".into(), "Hard".into()],
+            ..Default::default()
+        };
+
+        let result = repository.load_encounters_preview(1, 10, "".into(), filter).unwrap();
+        let ids: Vec<_> = result.encounters.iter().map(|encounter| encounter.preview.id as i64).collect();
+        assert_eq!(ids, vec![normal, hard]);
+        assert_eq!(result.total_encounters, 2);
+    }
+
+    #[test]
+    fn should_filter_by_participants() {
+        let repository = setup();
+
+        let bard = |name| TestPlayer { name, class_id: 204, character_id: 0, gear_score: 1700.0, dps: 100 };
+        let berserker = |name| TestPlayer { name, class_id: 102, character_id: 0, gear_score: 1700.0, dps: 100 };
+
+        let with_bard_alice = TestEncounter {
+            players: vec![bard("alice"), berserker("bob")],
+            ..Default::default()
+        }.insert(&repository);
+        let with_berserker_alice = TestEncounter {
+            players: vec![berserker("alice"), bard("carol")],
+            ..Default::default()
+        }.insert(&repository);
+        let with_bob_and_carol = TestEncounter {
+            players: vec![berserker("bob"), bard("carol")],
+            ..Default::default()
+        }.insert(&repository);
+
+        let search = |participants: Vec<Participant>, participant_match| {
+            let filter = EncounterFilter {
+                base: SearchFilter {
+                    sort: "fight_start".into(),
+                    order: 1,
+                    ..Default::default()
+                },
+                participants,
+                participant_match,
+                ..Default::default()
+            };
+
+            repository.load_encounters_preview(1, 10, "".into(), filter).unwrap()
+                .encounters
+                .iter()
+                .map(|encounter| encounter.preview.id as i64)
+                .collect::<Vec<_>>()
+        };
+
+        let alice_as_bard = Participant {
+            name: Some("alice".into()),
+            class_id: Some(204),
+            ..Default::default()
+        };
+        assert_eq!(search(vec![alice_as_bard], ParticipantMatch::All), vec![with_bard_alice]);
+
+        let bob = Participant { name: Some("bob".into()), ..Default::default() };
+        let carol = Participant { name: Some("carol".into()), ..Default::default() };
+        assert_eq!(search(vec![bob.clone(), carol.clone()], ParticipantMatch::All), vec![with_bob_and_carol]);
+        assert_eq!(
+            search(vec![bob, carol], ParticipantMatch::Any),
+            vec![with_bard_alice, with_berserker_alice, with_bob_and_carol]);
+
+        repository.get_connection().unwrap()
+            .execute(
+                "INSERT INTO entity (name, encounter_id, entity_type, class_id) VALUES ('Narok the Butcher', ?1, 'BOSS', 0)",
+                [with_bard_alice])
+            .unwrap();
+        let boss = Participant { name: Some("Narok the Butcher".into()), ..Default::default() };
+        assert!(search(vec![boss], ParticipantMatch::Any).is_empty());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,287 @@
+use rusqlite::{params_from_iter, types::Value};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::*, models::{EncounterFilter, EncounterPreviewItem, EncounterPreviewPage}};
+
+use super::{load_encounters_preview::{PreviewQuery, PREVIEW_COLUMNS}, SqliteRepository};
+
+/// Position of a row in the listing, the sort key of the row plus its id.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct PreviewCursor {
+    sort: String,
+    /// Direction of the listing, not of the walk, see `backward`.
+    ascending: bool,
+    value: serde_json::Value,
+    id: i64,
+    backward: bool,
+}
+
+impl PreviewCursor {
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        json.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn decode(cursor: &str) -> Result<Self> {
+        let invalid = || StoreError::InvalidFilter(format!("invalid cursor '{}'", cursor));
+
+        if cursor.len() % 2 != 0 || !cursor.is_ascii() {
+            return Err(invalid());
+        }
+
+        let json = (0..cursor.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&cursor[index..index + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+
+        serde_json::from_slice(&json).map_err(|_| invalid())
+    }
+}
+
+impl SqliteRepository {
+
+    pub(crate) fn load_encounters_preview_page_inner(
+        &self,
+        cursor: Option<String>,
+        page_size: i32,
+        search: String,
+        filter: EncounterFilter,
+        include_total: bool,
+    ) -> Result<EncounterPreviewPage> {
+        if page_size < 1 {
+            return Err(StoreError::InvalidFilter(format!("invalid page size {}", page_size)));
+        }
+
+        let preview_query = PreviewQuery::new(search, filter)?;
+        let cursor = cursor.as_deref().map(PreviewCursor::decode).transpose()?;
+        let sort = preview_query.sort;
+
+        if cursor.as_ref().is_some_and(|cursor| cursor.sort != sort.column() || cursor.ascending != preview_query.ascending) {
+            return Err(StoreError::InvalidFilter("cursor was created for a different sort or order".into()));
+        }
+
+        let backward = cursor.as_ref().is_some_and(|cursor| cursor.backward);
+        // walking backward reads the preceding rows in reverse order
+        let ascending = preview_query.ascending != backward;
+
+        let mut params = preview_query.params.clone();
+
+        let keyset_filter = match &cursor {
+            Some(cursor) => {
+                params.push(json_to_sql(&cursor.value));
+                params.push(cursor.id.into());
+                let comparison = if ascending { ">" } else { "<" };
+                format!("AND ({}, e.id) {} (?, ?)", sort.expression(), comparison)
+            },
+            None => "".to_string(),
+        };
+
+        let query = format!(
+            "SELECT {}, {}
+        {} {}
+        ORDER BY {}
+        LIMIT ?",
+            PREVIEW_COLUMNS,
+            sort.expression(),
+            preview_query.from,
+            keyset_filter,
+            preview_query.order_by(ascending)
+        );
+
+        // one extra row tells whether there is anything past this page
+        params.push((page_size + 1).into());
+
+        let connection = self.pool.get()?;
+        let mut statement = connection.prepare_cached(&query)?;
+
+        let mut rows: Vec<(EncounterPreviewItem, i64, Value)> = statement
+            .query_map(params_from_iter(params), |row| {
+                Ok((Self::map_to_row(row)?, row.get(0)?, row.get(11)?))
+            })?
+            .collect::<Result<_, _>>()?;
+
+        let has_more = rows.len() > page_size as usize;
+        rows.truncate(page_size as usize);
+
+        if backward {
+            rows.reverse();
+        }
+
+        let to_cursor = |(_, id, value): &(EncounterPreviewItem, i64, Value), backward: bool| PreviewCursor {
+            sort: sort.column().to_string(),
+            ascending: preview_query.ascending,
+            value: sql_to_json(value),
+            id: *id,
+            backward,
+        }.encode();
+
+        let (has_next, has_prev) = if backward {
+            (true, has_more)
+        } else {
+            (has_more, cursor.is_some())
+        };
+
+        let next_cursor = rows.last().filter(|_| has_next).map(|row| to_cursor(row, false));
+        let prev_cursor = rows.first().filter(|_| has_prev).map(|row| to_cursor(row, true));
+
+        let total_encounters = if include_total {
+            Some(Self::count_encounters_preview(&connection, &preview_query)?)
+        } else {
+            None
+        };
+
+        Ok(EncounterPreviewPage {
+            encounters: rows.into_iter().map(|(encounter, _, _)| encounter).collect(),
+            next_cursor,
+            prev_cursor,
+            total_encounters,
+        })
+    }
+}
+
+fn sql_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Integer(value) => (*value).into(),
+        Value::Real(value) => (*value).into(),
+        Value::Text(value) => value.clone().into(),
+        Value::Null | Value::Blob(_) => serde_json::Value::Null,
+    }
+}
+
+fn json_to_sql(value: &serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Number(number) => number
+            .as_i64()
+            .map(Value::Integer)
+            .or_else(|| number.as_f64().map(Value::Real))
+            .unwrap_or(Value::Null),
+        serde_json::Value::String(value) => Value::Text(value.clone()),
+        _ => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lost_metrics_core::models::SearchFilter;
+
+    use crate::{error::StoreError, repository::{test_utils::*, Repository}};
+
+    use super::PreviewCursor;
+
+    fn ids(page: &crate::models::EncounterPreviewPage) -> Vec<i64> {
+        page.encounters.iter().map(|encounter| encounter.preview.id as i64).collect()
+    }
+
+    #[test]
+    fn should_walk_pages_forward_and_back() {
+        let repository = setup();
+        let ids_by_age: Vec<_> = (0..5)
+            .map(|index| TestEncounter {
+                fight_start: 1_000_000 + index * 1000,
+                ..Default::default()
+            }.insert(&repository))
+            .collect();
+
+        let filter = || SearchFilter {
+            sort: "fight_start".into(),
+            ..Default::default()
+        }.into();
+
+        let first = repository.load_encounters_preview_page(None, 2, "".into(), filter(), true).unwrap();
+        assert_eq!(ids(&first), vec![ids_by_age[4], ids_by_age[3]]);
+        assert_eq!(first.total_encounters, Some(5));
+        assert!(first.prev_cursor.is_none());
+
+        // a new encounter must not shift the following pages
+        TestEncounter {
+            fight_start: 2_000_000,
+            ..Default::default()
+        }.insert(&repository);
+
+        let second = repository.load_encounters_preview_page(first.next_cursor, 2, "".into(), filter(), false).unwrap();
+        assert_eq!(ids(&second), vec![ids_by_age[2], ids_by_age[1]]);
+        assert_eq!(second.total_encounters, None);
+
+        let third = repository.load_encounters_preview_page(second.next_cursor.clone(), 2, "".into(), filter(), false).unwrap();
+        assert_eq!(ids(&third), vec![ids_by_age[0]]);
+        assert!(third.next_cursor.is_none());
+
+        let back = repository.load_encounters_preview_page(third.prev_cursor, 2, "".into(), filter(), false).unwrap();
+        assert_eq!(ids(&back), ids(&second));
+        assert_eq!(back.next_cursor, second.next_cursor);
+    }
+
+    #[test]
+    fn should_reject_cursor_from_other_sort() {
+        let repository = setup();
+        TestEncounter::default().insert(&repository);
+        TestEncounter::default().insert(&repository);
+
+        let filter = SearchFilter {
+            sort: "fight_start".into(),
+            ..Default::default()
+        };
+        let page = repository.load_encounters_preview_page(None, 1, "".into(), filter.into(), false).unwrap();
+
+        let filter = SearchFilter {
+            sort: "duration".into(),
+            ..Default::default()
+        };
+        let result = repository.load_encounters_preview_page(page.next_cursor.clone(), 1, "".into(), filter.into(), false);
+        assert!(matches!(result, Err(StoreError::InvalidFilter(_))));
+
+        let filter = SearchFilter {
+            sort: "fight_start".into(),
+            order: 1,
+            ..Default::default()
+        };
+        let result = repository.load_encounters_preview_page(page.next_cursor, 1, "".into(), filter.into(), false);
+        assert!(matches!(result, Err(StoreError::InvalidFilter(_))));
+    }
+
+    #[test]
+    fn should_return_structured_players() {
+        let repository = setup();
+        TestEncounter {
+            players: vec![
+                TestPlayer { name: "alice", class_id: 204, character_id: 1, gear_score: 1710.0, dps: 300 },
+                TestPlayer { name: "bob", class_id: 102, character_id: 2, gear_score: 1680.0, dps: 200 },
+            ],
+            ..Default::default()
+        }.insert(&repository);
+
+        let page = repository.load_encounters_preview_page(None, 10, "".into(), SearchFilter::default().into(), false).unwrap();
+        let item = &page.encounters[0];
+
+        assert_eq!(item.preview.names, vec!["alice".to_string(), "bob".to_string()]);
+        assert_eq!(item.preview.classes, vec![204, 102]);
+        assert_eq!(item.players[0].dps, 300);
+        assert_eq!(item.players[1].gear_score, 1680.0);
+    }
+
+    #[test]
+    fn should_reject_invalid_page_size() {
+        let repository = setup();
+        TestEncounter::default().insert(&repository);
+
+        for page_size in [0, -1, -2] {
+            let result = repository.load_encounters_preview_page(None, page_size, "".into(), SearchFilter::default().into(), false);
+            assert!(matches!(result, Err(StoreError::InvalidFilter(_))));
+        }
+    }
+
+    #[test]
+    fn should_round_trip_cursor() {
+        let cursor = PreviewCursor {
+            sort: "my_dps".into(),
+            ascending: false,
+            value: 100.into(),
+            id: 4,
+            backward: true,
+        };
+
+        assert_eq!(PreviewCursor::decode(&cursor.encode()).unwrap(), cursor);
+        assert!(PreviewCursor::decode("not a cursor").is_err());
+    }
+}
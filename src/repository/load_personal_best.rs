@@ -0,0 +1,78 @@
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{error::*, models::{PersonalBest, PersonalBestEntry, PersonalBestOf}};
+
+use super::{queries::{SELECT_PERSONAL_BEST_CLEAR, SELECT_PERSONAL_BEST_DPS}, SqliteRepository};
+
+impl SqliteRepository {
+
+    pub(crate) fn load_personal_best_inner(
+        &self,
+        connection: &Connection,
+        boss: &str,
+        difficulty: &str,
+        of: PersonalBestOf,
+    ) -> Result<PersonalBest> {
+        let (class_id, character_id) = match of {
+            PersonalBestOf::Class(class_id) => (Some(class_id as i64), None),
+            PersonalBestOf::Character(character_id) => (None, Some(character_id as i64)),
+        };
+
+        let mut best = PersonalBest::default();
+
+        for (query, entry) in [
+            (SELECT_PERSONAL_BEST_DPS, &mut best.dps),
+            (SELECT_PERSONAL_BEST_CLEAR, &mut best.clear_duration),
+        ] {
+            let mut statement = connection.prepare_cached(query)?;
+
+            *entry = statement
+                .query_row(params![boss, difficulty, class_id, character_id], |row| Ok(PersonalBestEntry {
+                    encounter_id: row.get(0)?,
+                    fight_start: row.get(1)?,
+                    value: row.get(2)?,
+                }))
+                .optional()?;
+        }
+
+        Ok(best)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{models::PersonalBestOf, repository::{test_utils::*, Repository}};
+
+    fn attempt(duration: i64, cleared: bool, players: Vec<TestPlayer>) -> TestEncounter {
+        TestEncounter {
+            duration,
+            cleared,
+            players,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn should_find_best_dps_and_clear_time() {
+        let repository = setup();
+        let bard = |dps| TestPlayer { name: "test", class_id: 204, character_id: 1, gear_score: 1700.0, dps };
+        let berserker = |dps| TestPlayer { name: "test", class_id: 102, character_id: 2, gear_score: 1700.0, dps };
+
+        let fastest = attempt(400_000, true, vec![bard(100)]).insert(&repository);
+        let highest = attempt(300_000, false, vec![bard(500)]).insert(&repository);
+        attempt(500_000, true, vec![bard(200)]).insert(&repository);
+        let other_class = attempt(200_000, true, vec![berserker(900)]).insert(&repository);
+        let connection = repository.get_connection().unwrap();
+
+        let best = repository.load_personal_best(&connection, "Narok the Butcher", "Hard", PersonalBestOf::Class(204)).unwrap();
+        assert_eq!(best.dps.as_ref().map(|entry| (entry.encounter_id, entry.value)), Some((highest, 500)));
+        assert_eq!(best.clear_duration.as_ref().map(|entry| (entry.encounter_id, entry.value)), Some((fastest, 400_000)));
+
+        let best = repository.load_personal_best(&connection, "Narok the Butcher", "Hard", PersonalBestOf::Character(2)).unwrap();
+        assert_eq!(best.dps.map(|entry| entry.encounter_id), Some(other_class));
+
+        let best = repository.load_personal_best(&connection, "Narok the Butcher", "Normal", PersonalBestOf::Class(204)).unwrap();
+        assert!(best.dps.is_none());
+        assert!(best.clear_duration.is_none());
+    }
+}
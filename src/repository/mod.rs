@@ -1,19 +1,34 @@
 mod load_encounters_preview;
+mod load_encounters_preview_page;
+mod load_boss_stats;
+mod load_personal_best;
+mod load_character_history;
+mod load_encounter;
 mod insert_encounter;
 mod insert_entities;
 mod insert_encounter_preview;
+mod delete_encounters;
+mod update_encounter_flags;
+mod encounter_tags;
+mod encounter_notes;
+mod sync_logs;
+mod retention;
+mod storage_stats;
+mod integrity;
+mod rebuild_previews;
 mod queries;
+#[cfg(test)]
+pub(crate) mod test_utils;
 
 use lost_metrics_core::models::*;
 use r2d2::{Pool, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::Connection;
-use anyhow::*;
+use rusqlite::{params, Connection};
 
 #[cfg(test)]
 use mockall::automock;
 
-use crate::models::*;
+use crate::{error::*, models::*};
 
 #[cfg_attr(test, automock)]
 pub trait Repository : Send + Sync + 'static {
@@ -23,8 +38,37 @@ pub trait Repository : Send + Sync + 'static {
         page: i32,
         page_size: i32,
         search: String,
-        filter: SearchFilter,
-    ) -> Result<EncountersOverview>;
+        filter: EncounterFilter,
+    ) -> Result<EncounterPreviewOverview>;
+    fn load_encounters_preview_page(
+        &self,
+        cursor: Option<String>,
+        page_size: i32,
+        search: String,
+        filter: EncounterFilter,
+        include_total: bool,
+    ) -> Result<EncounterPreviewPage>;
+    /// Per boss and difficulty aggregates over the previews matching the search and filter.
+    fn load_boss_stats(
+        &self,
+        search: String,
+        filter: EncounterFilter,
+    ) -> Result<Vec<BossStats>>;
+    /// Takes a connection so the lookup can share the transaction of the insert it is compared against.
+    fn load_personal_best(
+        &self,
+        connection: &Connection,
+        boss: &str,
+        difficulty: &str,
+        of: PersonalBestOf,
+    ) -> Result<PersonalBest>;
+    /// Dps per boss and difficulty and gear score over time, by character id and falling back to name.
+    fn load_character_history(
+        &self,
+        character_id: u64,
+        name: &str,
+    ) -> Result<CharacterHistory>;
+    fn load_encounter(&self, id: i64) -> Result<Encounter>;
     fn insert_encounter(
         &self,
         connection: &Connection,
@@ -39,6 +83,90 @@ pub trait Repository : Send + Sync + 'static {
         connection: &Connection,
         encounter_id: i64,
         encounter_preview: EncounterPreviewDb<'a>) -> Result<()>;
+    fn delete_encounters(
+        &self,
+        connection: &Connection,
+        ids: &[i64]) -> Result<usize>;
+    /// Fails with [`StoreError::NotFound`] if the encounter doesn't exist.
+    fn set_favorite(
+        &self,
+        connection: &Connection,
+        id: i64,
+        favorite: bool) -> Result<()>;
+    fn set_favorite_many(
+        &self,
+        connection: &Connection,
+        ids: &[i64],
+        favorite: bool) -> Result<()>;
+    /// Fails with [`StoreError::NotFound`] if the encounter doesn't exist.
+    fn set_cleared(
+        &self,
+        connection: &Connection,
+        id: i64,
+        cleared: bool) -> Result<()>;
+    fn set_cleared_many(
+        &self,
+        connection: &Connection,
+        ids: &[i64],
+        cleared: bool) -> Result<()>;
+    fn add_tags(
+        &self,
+        connection: &Connection,
+        encounter_id: i64,
+        tags: &[String]) -> Result<()>;
+    fn remove_tags(
+        &self,
+        connection: &Connection,
+        encounter_id: i64,
+        tags: &[String]) -> Result<usize>;
+    fn load_tags(&self, encounter_id: i64) -> Result<Vec<String>>;
+    /// Every tag in use, with the number of encounters that have it.
+    fn load_tag_summaries(&self) -> Result<Vec<TagSummary>>;
+    fn set_note(
+        &self,
+        connection: &Connection,
+        encounter_id: i64,
+        note: &str) -> Result<()>;
+    fn delete_note(
+        &self,
+        connection: &Connection,
+        encounter_id: i64) -> Result<()>;
+    fn load_note(&self, encounter_id: i64) -> Result<Option<String>>;
+    /// Encounters that were never uploaded, oldest first, including failed uploads once they are retried.
+    fn load_unsynced_encounters(&self, limit: i64) -> Result<Vec<i64>>;
+    fn load_failed_syncs(&self, limit: i64) -> Result<Vec<i64>>;
+    fn load_sync_log(&self, encounter_id: i64) -> Result<Option<SyncLog>>;
+    fn record_sync_success(
+        &self,
+        connection: &Connection,
+        encounter_id: i64,
+        upstream_id: &str) -> Result<()>;
+    fn record_sync_failure(
+        &self,
+        connection: &Connection,
+        encounter_id: i64) -> Result<()>;
+    /// Marks every failed upload without an upstream id as unsynced again, returns how many failed uploads there were.
+    fn retry_failed_syncs(&self, connection: &Connection) -> Result<usize>;
+    /// Candidates for pruning, favorites, clears and tagged encounters are never included.
+    fn load_prunable_encounters(
+        &self,
+        fight_start_before: Option<i64>,
+        max_per_boss: Option<u32>) -> Result<Vec<i64>>;
+    /// Compacts the search index and releases free pages, meant to run after large deletes.
+    fn optimize_storage(&self) -> Result<()>;
+    fn load_storage_stats(&self) -> Result<StorageStats>;
+    /// [`StorageStats::used_bytes`] alone, without scanning the tables and columns for the breakdown.
+    fn load_used_bytes(&self) -> Result<i64>;
+    /// Encounters that may be evicted to stay under a size budget with their estimated size, oldest first.
+    fn load_evictable_encounters(&self) -> Result<Vec<EncounterCost>>;
+    fn check_integrity(&self) -> Result<IntegrityReport>;
+    fn repair(&self, connection: &Connection) -> Result<RepairReport>;
+    /// Rebuilds the given previews or all of them when `ids` is `None`, creating the missing ones.
+    /// `boss_only_damage` is only stored on the preview, so it can't be recomputed and is kept, or 0 on a new preview.
+    /// Fails with [`StoreError::NotFound`] on the first id without an encounter, returns how many previews were rebuilt.
+    // automock needs the lifetime spelled out inside the Option
+    #[allow(clippy::needless_lifetimes)]
+    fn rebuild_previews<'a>(&self, connection: &Connection, ids: Option<&'a [i64]>) -> Result<usize>;
 }
 
 pub struct SqliteRepository {
@@ -57,9 +185,46 @@ impl Repository for SqliteRepository {
         page: i32,
         page_size: i32,
         search: String,
-        filter: SearchFilter) -> Result<EncountersOverview> {
+        filter: EncounterFilter) -> Result<EncounterPreviewOverview> {
         self.load_encounters_preview_inner(page, page_size, search, filter)
     }
+
+    fn load_encounters_preview_page(
+        &self,
+        cursor: Option<String>,
+        page_size: i32,
+        search: String,
+        filter: EncounterFilter,
+        include_total: bool) -> Result<EncounterPreviewPage> {
+        self.load_encounters_preview_page_inner(cursor, page_size, search, filter, include_total)
+    }
+
+    fn load_boss_stats(
+        &self,
+        search: String,
+        filter: EncounterFilter) -> Result<Vec<BossStats>> {
+        self.load_boss_stats_inner(search, filter)
+    }
+
+    fn load_personal_best(
+        &self,
+        connection: &Connection,
+        boss: &str,
+        difficulty: &str,
+        of: PersonalBestOf) -> Result<PersonalBest> {
+        self.load_personal_best_inner(connection, boss, difficulty, of)
+    }
+
+    fn load_character_history(
+        &self,
+        character_id: u64,
+        name: &str) -> Result<CharacterHistory> {
+        self.load_character_history_inner(character_id, name)
+    }
+
+    fn load_encounter(&self, id: i64) -> Result<Encounter> {
+        self.load_encounter_inner(id)
+    }
     
     fn insert_entities<'a>(&self,
         connection: &Connection,
@@ -82,6 +247,154 @@ impl Repository for SqliteRepository {
         encounter_preview: EncounterPreviewDb<'a>) -> Result<()> {
         self.insert_encounter_preview_inner(connection, encounter_id, encounter_preview)
     }
+
+    fn delete_encounters(
+        &self,
+        connection: &Connection,
+        ids: &[i64]) -> Result<usize> {
+        self.delete_encounters_inner(connection, ids)
+    }
+
+    fn set_favorite(
+        &self,
+        connection: &Connection,
+        id: i64,
+        favorite: bool) -> Result<()> {
+        self.set_favorite_inner(connection, &[id], favorite)
+    }
+
+    fn set_favorite_many(
+        &self,
+        connection: &Connection,
+        ids: &[i64],
+        favorite: bool) -> Result<()> {
+        self.set_favorite_inner(connection, ids, favorite)
+    }
+
+    fn set_cleared(
+        &self,
+        connection: &Connection,
+        id: i64,
+        cleared: bool) -> Result<()> {
+        self.set_cleared_inner(connection, &[id], cleared)
+    }
+
+    fn set_cleared_many(
+        &self,
+        connection: &Connection,
+        ids: &[i64],
+        cleared: bool) -> Result<()> {
+        self.set_cleared_inner(connection, ids, cleared)
+    }
+
+    fn add_tags(
+        &self,
+        connection: &Connection,
+        encounter_id: i64,
+        tags: &[String]) -> Result<()> {
+        self.add_tags_inner(connection, encounter_id, tags)
+    }
+
+    fn remove_tags(
+        &self,
+        connection: &Connection,
+        encounter_id: i64,
+        tags: &[String]) -> Result<usize> {
+        self.remove_tags_inner(connection, encounter_id, tags)
+    }
+
+    fn load_tags(&self, encounter_id: i64) -> Result<Vec<String>> {
+        self.load_tags_inner(encounter_id)
+    }
+
+    fn load_tag_summaries(&self) -> Result<Vec<TagSummary>> {
+        self.load_tag_summaries_inner()
+    }
+
+    fn set_note(
+        &self,
+        connection: &Connection,
+        encounter_id: i64,
+        note: &str) -> Result<()> {
+        self.set_note_inner(connection, encounter_id, note)
+    }
+
+    fn delete_note(
+        &self,
+        connection: &Connection,
+        encounter_id: i64) -> Result<()> {
+        self.delete_note_inner(connection, encounter_id)
+    }
+
+    fn load_note(&self, encounter_id: i64) -> Result<Option<String>> {
+        self.load_note_inner(encounter_id)
+    }
+
+    fn load_unsynced_encounters(&self, limit: i64) -> Result<Vec<i64>> {
+        self.load_unsynced_encounters_inner(limit)
+    }
+
+    fn load_failed_syncs(&self, limit: i64) -> Result<Vec<i64>> {
+        self.load_failed_syncs_inner(limit)
+    }
+
+    fn load_sync_log(&self, encounter_id: i64) -> Result<Option<SyncLog>> {
+        self.load_sync_log_inner(encounter_id)
+    }
+
+    fn record_sync_success(
+        &self,
+        connection: &Connection,
+        encounter_id: i64,
+        upstream_id: &str) -> Result<()> {
+        self.record_sync_success_inner(connection, encounter_id, upstream_id)
+    }
+
+    fn record_sync_failure(
+        &self,
+        connection: &Connection,
+        encounter_id: i64) -> Result<()> {
+        self.record_sync_failure_inner(connection, encounter_id)
+    }
+
+    fn retry_failed_syncs(&self, connection: &Connection) -> Result<usize> {
+        self.retry_failed_syncs_inner(connection)
+    }
+
+    fn load_prunable_encounters(
+        &self,
+        fight_start_before: Option<i64>,
+        max_per_boss: Option<u32>) -> Result<Vec<i64>> {
+        self.load_prunable_encounters_inner(fight_start_before, max_per_boss)
+    }
+
+    fn optimize_storage(&self) -> Result<()> {
+        self.optimize_storage_inner()
+    }
+
+    fn load_storage_stats(&self) -> Result<StorageStats> {
+        self.load_storage_stats_inner()
+    }
+
+    fn load_used_bytes(&self) -> Result<i64> {
+        self.load_used_bytes_inner()
+    }
+
+    fn load_evictable_encounters(&self) -> Result<Vec<EncounterCost>> {
+        self.load_evictable_encounters_inner()
+    }
+
+    fn check_integrity(&self) -> Result<IntegrityReport> {
+        self.check_integrity_inner()
+    }
+
+    fn repair(&self, connection: &Connection) -> Result<RepairReport> {
+        self.repair_inner(connection)
+    }
+
+    fn rebuild_previews(&self, connection: &Connection, ids: Option<&[i64]>) -> Result<usize> {
+        self.rebuild_previews_inner(connection, ids)
+    }
 }
 
 impl SqliteRepository {
@@ -91,4 +404,15 @@ impl SqliteRepository {
             pool,
         }
     }
+
+    fn ensure_encounter_exists(connection: &Connection, encounter_id: i64) -> Result<()> {
+        let mut statement = connection.prepare_cached(queries::SELECT_ENCOUNTER_EXISTS)?;
+        let exists: bool = statement.query_row(params![encounter_id], |row| row.get(0))?;
+
+        if !exists {
+            return Err(StoreError::NotFound(encounter_id));
+        }
+
+        Ok(())
+    }
 }
\ No newline at end of file
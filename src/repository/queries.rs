@@ -57,6 +57,314 @@ INSERT INTO encounter_preview (
     local_player,
     my_dps,
     cleared,
-    boss_only_damage
+    boss_only_damage,
+    player_data
 ) 
-VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)";
\ No newline at end of file
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)";
+
+pub const SELECT_ENCOUNTER: &str = r"
+SELECT
+    ep.fight_start,
+    ep.current_boss,
+    ep.duration,
+    ep.difficulty,
+    ep.local_player,
+    ep.favorite,
+    ep.cleared,
+    ep.boss_only_damage,
+    e.last_combat_packet,
+    e.total_damage_dealt,
+    e.top_damage_dealt,
+    e.total_damage_taken,
+    e.top_damage_taken,
+    e.dps,
+    e.buffs,
+    e.debuffs,
+    e.total_shielding,
+    e.total_effective_shielding,
+    e.applied_shield_buffs,
+    e.misc,
+    e.boss_hp_log,
+    e.stagger_log
+FROM encounter e
+JOIN encounter_preview ep ON ep.id = e.id
+WHERE e.id = ?1";
+
+pub const SELECT_ENTITIES: &str = r"
+SELECT
+    name,
+    character_id,
+    npc_id,
+    entity_type,
+    class_id,
+    class,
+    gear_score,
+    current_hp,
+    max_hp,
+    is_dead,
+    skills,
+    damage_stats,
+    skill_stats,
+    engravings,
+    gear_hash,
+    ark_passive_active,
+    spec,
+    ark_passive_data
+FROM entity
+WHERE encounter_id = ?1";
+
+pub const DELETE_SYNC_LOG: &str = "DELETE FROM sync_logs WHERE encounter_id = ?1";
+
+pub const DELETE_ENTITIES: &str = "DELETE FROM entity WHERE encounter_id = ?1";
+
+pub const DELETE_ENCOUNTER_PREVIEW: &str = "DELETE FROM encounter_preview WHERE id = ?1";
+
+pub const DELETE_ENCOUNTER: &str = "DELETE FROM encounter WHERE id = ?1";
+
+pub const UPDATE_FAVORITE: &str = "UPDATE encounter_preview SET favorite = ?2 WHERE id = ?1";
+
+pub const UPDATE_CLEARED: &str = "UPDATE encounter_preview SET cleared = ?2 WHERE id = ?1";
+
+/// Keeps `misc.raidClear` in step with the preview flag, rebuilding the preview reads it back from there.
+pub const UPDATE_RAID_CLEAR: &str = r"
+UPDATE encounter
+SET misc = json_set(IFNULL(misc, '{}'), '$.raidClear', json(CASE WHEN ?2 THEN 'true' ELSE 'false' END))
+WHERE id = ?1";
+
+pub const SELECT_ENCOUNTER_EXISTS: &str = "SELECT EXISTS (SELECT 1 FROM encounter WHERE id = ?1)";
+
+pub const INSERT_TAG: &str = "INSERT OR IGNORE INTO encounter_tag (encounter_id, tag) VALUES (?1, ?2)";
+
+pub const DELETE_TAG: &str = "DELETE FROM encounter_tag WHERE encounter_id = ?1 AND tag = ?2";
+
+pub const SELECT_TAGS: &str = "SELECT tag FROM encounter_tag WHERE encounter_id = ?1 ORDER BY tag";
+
+pub const SELECT_TAG_SUMMARIES: &str = r"
+SELECT
+    tag,
+    COUNT(*)
+FROM encounter_tag
+GROUP BY tag
+ORDER BY tag";
+
+pub const UPSERT_NOTE: &str = r"
+INSERT INTO encounter_note (encounter_id, note, updated_at)
+VALUES (?1, ?2, ?3)
+ON CONFLICT (encounter_id) DO UPDATE SET
+    note = excluded.note,
+    updated_at = excluded.updated_at";
+
+pub const DELETE_NOTE: &str = "DELETE FROM encounter_note WHERE encounter_id = ?1";
+
+pub const SELECT_NOTE: &str = "SELECT note FROM encounter_note WHERE encounter_id = ?1";
+
+pub const SELECT_PERSONAL_BEST_DPS: &str = r"
+SELECT
+    p.id,
+    p.fight_start,
+    en.dps
+FROM entity en
+JOIN encounter_preview p ON p.id = en.encounter_id
+WHERE p.current_boss = ?1
+    AND p.difficulty = ?2
+    AND en.entity_type = 'PLAYER'
+    AND (?3 IS NULL OR (en.name = p.local_player AND en.class_id = ?3))
+    AND (?4 IS NULL OR en.character_id = ?4)
+ORDER BY en.dps DESC, p.id
+LIMIT 1";
+
+pub const SELECT_PERSONAL_BEST_CLEAR: &str = r"
+SELECT
+    p.id,
+    p.fight_start,
+    p.duration
+FROM entity en
+JOIN encounter_preview p ON p.id = en.encounter_id
+WHERE p.current_boss = ?1
+    AND p.difficulty = ?2
+    AND p.cleared = 1
+    AND en.entity_type = 'PLAYER'
+    AND (?3 IS NULL OR (en.name = p.local_player AND en.class_id = ?3))
+    AND (?4 IS NULL OR en.character_id = ?4)
+ORDER BY p.duration, p.id
+LIMIT 1";
+
+pub const SELECT_CHARACTER_HISTORY: &str = r"
+SELECT
+    p.id,
+    p.fight_start,
+    IFNULL(p.current_boss, ''),
+    IFNULL(p.difficulty, ''),
+    en.class_id,
+    en.spec,
+    en.gear_score,
+    en.dps
+FROM entity en
+JOIN encounter_preview p ON p.id = en.encounter_id
+WHERE en.entity_type = 'PLAYER'
+    AND (
+        (?1 != 0 AND en.character_id = ?1)
+        OR ((?1 = 0 OR IFNULL(en.character_id, 0) = 0) AND en.name = ?2)
+    )
+ORDER BY p.fight_start, p.id";
+
+pub const SELECT_UNSYNCED_ENCOUNTERS: &str = r"
+SELECT e.id
+FROM encounter e
+LEFT JOIN sync_logs s ON s.encounter_id = e.id
+WHERE s.encounter_id IS NULL OR (s.failed = 0 AND s.upstream_id IS NULL)
+ORDER BY e.id
+LIMIT ?1";
+
+pub const SELECT_FAILED_SYNCS: &str = "SELECT encounter_id FROM sync_logs WHERE failed = 1 ORDER BY encounter_id LIMIT ?1";
+
+pub const SELECT_SYNC_LOG: &str = "SELECT encounter_id, upstream_id, failed FROM sync_logs WHERE encounter_id = ?1";
+
+pub const UPSERT_SYNC_SUCCESS: &str = r"
+INSERT INTO sync_logs (encounter_id, upstream_id, failed)
+VALUES (?1, ?2, 0)
+ON CONFLICT (encounter_id) DO UPDATE SET
+    upstream_id = excluded.upstream_id,
+    failed = 0";
+
+/// Keeps the upstream id of an earlier successful upload.
+pub const UPSERT_SYNC_FAILURE: &str = r"
+INSERT INTO sync_logs (encounter_id, upstream_id, failed)
+VALUES (?1, NULL, 1)
+ON CONFLICT (encounter_id) DO UPDATE SET
+    failed = 1";
+
+/// A failed row without an upstream id is pending again, one with an upstream id already reached upstream and counts as synced.
+pub const RESET_FAILED_SYNC_LOGS: &str = "UPDATE sync_logs SET failed = 0 WHERE failed = 1";
+
+pub const SELECT_PRUNABLE_ENCOUNTERS: &str = r"
+SELECT id
+FROM (
+    SELECT
+        e.id,
+        e.fight_start,
+        e.favorite,
+        e.cleared,
+        ROW_NUMBER() OVER (PARTITION BY e.current_boss ORDER BY e.fight_start DESC, e.id DESC) AS position
+    FROM encounter_preview e
+)
+WHERE favorite = 0
+    AND IFNULL(cleared, 0) = 0
+    AND id NOT IN (SELECT encounter_id FROM encounter_tag)
+    AND (
+        (?1 IS NOT NULL AND fight_start < ?1)
+        OR (?2 IS NOT NULL AND position > ?2)
+    )
+ORDER BY id";
+
+pub const SELECT_USED_BYTES: &str = r"
+SELECT
+    (page_count - freelist_count) * page_size,
+    freelist_count * page_size
+FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()";
+
+pub const SELECT_TABLE_SIZES: &str = r"
+SELECT
+    name,
+    SUM(pgsize)
+FROM dbstat
+GROUP BY name
+ORDER BY 2 DESC, 1";
+
+pub const SELECT_EVICTABLE_ENCOUNTERS: &str = r"
+SELECT
+    p.id,
+    IFNULL(LENGTH(e.buffs), 0)
+        + IFNULL(LENGTH(e.debuffs), 0)
+        + IFNULL(LENGTH(e.applied_shield_buffs), 0)
+        + IFNULL(LENGTH(e.boss_hp_log), 0)
+        + IFNULL(LENGTH(CAST(e.misc AS BLOB)), 0)
+        + IFNULL(LENGTH(CAST(e.stagger_log AS BLOB)), 0)
+        + IFNULL((
+            SELECT SUM(IFNULL(LENGTH(skills), 0) + IFNULL(LENGTH(damage_stats), 0))
+            FROM entity
+            WHERE encounter_id = p.id
+        ), 0)
+FROM encounter_preview p
+JOIN encounter e ON e.id = p.id
+WHERE p.favorite = 0
+    AND IFNULL(p.cleared, 0) = 0
+    AND p.id NOT IN (SELECT encounter_id FROM encounter_tag)
+ORDER BY p.fight_start, p.id";
+
+pub const SELECT_ORPHANED_ENTITIES: &str = r"
+SELECT DISTINCT encounter_id
+FROM entity
+WHERE encounter_id NOT IN (SELECT id FROM encounter)
+ORDER BY encounter_id";
+
+pub const SELECT_ORPHANED_PREVIEWS: &str = r"
+SELECT id
+FROM encounter_preview
+WHERE id NOT IN (SELECT id FROM encounter)
+ORDER BY id";
+
+pub const SELECT_ENCOUNTERS_WITHOUT_PREVIEW: &str = r"
+SELECT id
+FROM encounter
+WHERE id NOT IN (SELECT id FROM encounter_preview)
+ORDER BY id";
+
+pub const DELETE_ORPHANED_ENTITIES: &str = "DELETE FROM entity WHERE encounter_id NOT IN (SELECT id FROM encounter)";
+
+pub const DELETE_ORPHANED_PREVIEWS: &str = "DELETE FROM encounter_preview WHERE id NOT IN (SELECT id FROM encounter)";
+
+/// Recreates a missing preview from `encounter` and `entity`, an existing one gets the player columns bound by the caller
+/// and `cleared` again.
+pub const UPSERT_PREVIEW_FROM_ENCOUNTER: &str = r"
+INSERT INTO encounter_preview (
+    id,
+    fight_start,
+    current_boss,
+    duration,
+    players,
+    difficulty,
+    local_player,
+    my_dps,
+    cleared,
+    boss_only_damage,
+    player_data
+)
+SELECT
+    e.id,
+    IFNULL(e.ntp_fight_start, e.last_combat_packet - e.duration),
+    IFNULL((
+        SELECT name
+        FROM entity
+        WHERE encounter_id = e.id AND entity_type = 'BOSS'
+        ORDER BY max_hp DESC
+        LIMIT 1
+    ), ''),
+    e.duration,
+    ?2,
+    '',
+    '',
+    ?3,
+    IFNULL(json_extract(e.misc, '$.raidClear'), 0),
+    0,
+    ?4
+FROM (
+    SELECT
+        id,
+        last_combat_packet,
+        misc,
+        json_extract(misc, '$.ntpFightStart') AS ntp_fight_start,
+        -- at least a second like a save counts it, listings skip previews without a duration
+        MAX(CASE
+            WHEN dps > 0 THEN total_damage_dealt * 1000 / dps
+            ELSE last_combat_packet - IFNULL(json_extract(misc, '$.ntpFightStart'), last_combat_packet)
+        END, 1000) AS duration
+    FROM encounter
+    WHERE id = ?1
+) e
+WHERE e.id = ?1
+ON CONFLICT(id) DO UPDATE SET
+    players = excluded.players,
+    my_dps = excluded.my_dps,
+    cleared = excluded.cleared,
+    player_data = excluded.player_data";
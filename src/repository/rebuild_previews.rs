@@ -0,0 +1,217 @@
+use hashbrown::HashSet;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::json;
+
+use crate::{error::*, utils::{load_preview_players, to_players_column}};
+
+use super::{queries::UPSERT_PREVIEW_FROM_ENCOUNTER, SqliteRepository};
+
+impl SqliteRepository {
+
+    /// Recomputes the player columns of the previews from `entity` with the same filter as a save,
+    /// and `cleared` from `misc.raidClear`, which [`SqliteRepository::set_cleared_inner`] keeps up to date.
+    /// `favorite` is left as is.
+    ///
+    /// A missing preview is created again with what `encounter` still has: the start comes from `misc.ntpFightStart`
+    /// when it was recorded, the duration is derived from the dps and is at least a second so the preview is listed,
+    /// and the boss is the one with the most hp. Only the preview ever stored the difficulty and local player,
+    /// so those are left empty.
+    /// The triggers refresh `encounter_search` for each row, a full rebuild also rebuilds the index.
+    pub(crate) fn rebuild_previews_inner(&self, connection: &Connection, ids: Option<&[i64]>) -> Result<usize> {
+        let Some(ids) = ids else {
+            let ids = connection
+                .prepare("SELECT id FROM encounter ORDER BY id")?
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<Vec<i64>, _>>()?;
+
+            let rebuilt = Self::rebuild_previews_of(connection, &ids)?;
+            connection.execute("INSERT INTO encounter_search(encounter_search) VALUES('rebuild')", [])?;
+
+            return Ok(rebuilt);
+        };
+
+        Self::rebuild_previews_of(connection, ids)
+    }
+
+    fn rebuild_previews_of(connection: &Connection, ids: &[i64]) -> Result<usize> {
+        let mut select_local_player = connection.prepare_cached("SELECT local_player FROM encounter_preview WHERE id = ?1")?;
+        let mut upsert = connection.prepare_cached(UPSERT_PREVIEW_FROM_ENCOUNTER)?;
+        let mut seen = HashSet::new();
+        let mut rebuilt = 0;
+
+        for &id in ids {
+            if !seen.insert(id) {
+                continue;
+            }
+
+            let local_player: String = select_local_player
+                .query_row([id], |row| row.get::<_, Option<String>>(0))
+                .optional()?
+                .flatten()
+                .unwrap_or_default();
+
+            let players = load_preview_players(connection, id, &local_player)?;
+            let my_dps = players
+                .iter()
+                .find(|player| player.name == local_player)
+                .map(|player| player.dps)
+                .unwrap_or_default();
+
+            let params = params![id, to_players_column(&players), my_dps, json!(players)];
+
+            if upsert.execute(params)? == 0 {
+                return Err(StoreError::NotFound(id));
+            }
+
+            rebuilt += 1;
+        }
+
+        Ok(rebuilt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lost_metrics_core::models::SearchFilter;
+
+    use crate::{error::StoreError, repository::{test_utils::*, Repository}};
+
+    fn break_preview(repository: &impl Repository, id: i64) {
+        repository
+            .get_connection()
+            .unwrap()
+            .execute("UPDATE encounter_preview SET players = '', player_data = NULL, my_dps = 0 WHERE id = ?1", [id])
+            .unwrap();
+    }
+
+    fn rebuild(repository: &impl Repository, ids: &[i64]) -> usize {
+        let mut connection = repository.get_connection().unwrap();
+        let transaction = connection.transaction().unwrap();
+        let rebuilt = repository.rebuild_previews(&transaction, Some(ids)).unwrap();
+        transaction.commit().unwrap();
+        rebuilt
+    }
+
+    fn load_cleared(repository: &impl Repository, id: i64) -> Option<bool> {
+        repository
+            .get_connection()
+            .unwrap()
+            .query_row("SELECT cleared FROM encounter_preview WHERE id = ?1", [id], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn should_rebuild_selected_previews() {
+        let repository = setup();
+        let id = TestEncounter {
+            players: vec![
+                TestPlayer { name: "alice", class_id: 204, character_id: 1, gear_score: 1710.0, dps: 300 },
+                TestPlayer { name: "test", class_id: 102, character_id: 2, gear_score: 1680.0, dps: 200 },
+            ],
+            ..Default::default()
+        }.insert(&repository);
+        let other = TestEncounter::default().insert(&repository);
+
+        break_preview(&repository, id);
+        break_preview(&repository, other);
+
+        assert_eq!(rebuild(&repository, &[id]), 1);
+
+        let connection = repository.get_connection().unwrap();
+        let page = repository.load_encounters_preview_page(None, 10, "alice".into(), SearchFilter::default().into(), false).unwrap();
+        let item = &page.encounters[0];
+
+        assert_eq!(page.encounters.len(), 1);
+        assert_eq!(item.preview.names, vec!["alice".to_string(), "test".to_string()]);
+        assert_eq!(item.preview.my_dps, 200);
+        assert!(item.preview.cleared);
+        assert_eq!(item.players[0].gear_score, 1710.0);
+
+        let players: String = connection
+            .query_row("SELECT players FROM encounter_preview WHERE id = ?1", [other], |row| row.get(0))
+            .unwrap();
+        assert_eq!(players, "");
+    }
+
+    #[test]
+    fn should_rebuild_all_previews() {
+        let repository = setup();
+        let ids: Vec<_> = (0..3).map(|_| TestEncounter::default().insert(&repository)).collect();
+
+        for id in &ids {
+            break_preview(&repository, *id);
+        }
+
+        let mut connection = repository.get_connection().unwrap();
+        let transaction = connection.transaction().unwrap();
+        assert_eq!(repository.rebuild_previews(&transaction, None).unwrap(), 3);
+        transaction.commit().unwrap();
+
+        let page = repository.load_encounters_preview_page(None, 10, "test".into(), SearchFilter::default().into(), false).unwrap();
+        assert_eq!(page.encounters.len(), 3);
+        assert!(repository.check_integrity().unwrap().is_ok());
+    }
+
+    #[test]
+    fn should_recompute_cleared_from_encounter() {
+        let repository = setup();
+        let not_cleared = TestEncounter { cleared: false, ..Default::default() }.insert(&repository);
+        let marked_cleared = TestEncounter { cleared: false, ..Default::default() }.insert(&repository);
+        let wrongly_cleared = TestEncounter { cleared: false, ..Default::default() }.insert(&repository);
+
+        let connection = repository.get_connection().unwrap();
+        repository.set_cleared(&connection, marked_cleared, true).unwrap();
+        connection.execute("UPDATE encounter_preview SET cleared = 1 WHERE id = ?1", [wrongly_cleared]).unwrap();
+        drop(connection);
+
+        assert_eq!(rebuild(&repository, &[not_cleared, marked_cleared, wrongly_cleared]), 3);
+
+        assert_eq!(load_cleared(&repository, not_cleared), Some(false));
+        assert_eq!(load_cleared(&repository, marked_cleared), Some(true));
+        assert_eq!(load_cleared(&repository, wrongly_cleared), Some(false));
+    }
+
+    #[test]
+    fn should_recreate_missing_preview() {
+        let repository = setup();
+        let id = TestEncounter::default().insert(&repository);
+        let not_cleared = TestEncounter { cleared: false, ..Default::default() }.insert(&repository);
+
+        repository
+            .get_connection()
+            .unwrap()
+            .execute_batch(&format!("
+                UPDATE encounter SET total_damage_dealt = 60000, dps = 100;
+                DELETE FROM encounter_preview WHERE id IN ({}, {});
+                ", id, not_cleared))
+            .unwrap();
+
+        assert_eq!(rebuild(&repository, &[id, not_cleared]), 2);
+
+        let page = repository.load_encounters_preview_page(None, 10, "test".into(), SearchFilter::default().into(), false).unwrap();
+        let item = page.encounters.iter().find(|item| item.preview.id as i64 == id).unwrap();
+        assert_eq!(page.encounters.len(), 2);
+        assert_eq!(item.preview.duration, 600_000);
+        assert_eq!(item.players[0].name, "test");
+        assert_eq!(load_cleared(&repository, id), Some(true));
+        assert_eq!(load_cleared(&repository, not_cleared), Some(false));
+        assert!(repository.check_integrity().unwrap().is_ok());
+    }
+
+    #[test]
+    fn should_count_duplicate_ids_once() {
+        let repository = setup();
+        let id = TestEncounter::default().insert(&repository);
+
+        assert_eq!(rebuild(&repository, &[id, id, id]), 1);
+    }
+
+    #[test]
+    fn should_fail_on_unknown_encounter() {
+        let repository = setup();
+        let connection = repository.get_connection().unwrap();
+
+        let result = repository.rebuild_previews(&connection, Some(&[404]));
+        assert!(matches!(result, Err(StoreError::NotFound(404))));
+    }
+}
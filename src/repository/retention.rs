@@ -0,0 +1,128 @@
+use log::*;
+use rusqlite::params;
+
+use crate::error::*;
+
+use super::{queries::SELECT_PRUNABLE_ENCOUNTERS, SqliteRepository};
+
+impl SqliteRepository {
+
+    /// Encounters started before `fight_start_before` or past the `max_per_boss` most recent of their boss,
+    /// leaving out favorites, clears and tagged encounters.
+    pub(crate) fn load_prunable_encounters_inner(
+        &self,
+        fight_start_before: Option<i64>,
+        max_per_boss: Option<u32>,
+    ) -> Result<Vec<i64>> {
+        let connection = self.pool.get()?;
+        let mut statement = connection.prepare_cached(SELECT_PRUNABLE_ENCOUNTERS)?;
+
+        let ids = statement
+            .query_map(params![fight_start_before, max_per_boss], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(ids)
+    }
+
+    /// Merges the `encounter_search` index segments and hands free pages back to the file system.
+    ///
+    /// Databases created before `auto_vacuum = INCREMENTAL` was set on new connections are converted
+    /// with a one-time full `VACUUM`, later calls only release the free pages.
+    pub(crate) fn optimize_storage_inner(&self) -> Result<()> {
+        let connection = self.pool.get()?;
+
+        connection.execute("INSERT INTO encounter_search(encounter_search) VALUES('optimize')", [])?;
+
+        let auto_vacuum: i64 = connection.query_row("PRAGMA auto_vacuum", [], |row| row.get(0))?;
+
+        if auto_vacuum == 0 {
+            info!("converting database to incremental auto vacuum");
+            connection.execute_batch(
+                "
+                PRAGMA auto_vacuum = INCREMENTAL;
+                VACUUM;
+                ")?;
+
+            return Ok(());
+        }
+
+        // each step releases a single page, so it has to run until done
+        let mut statement = connection.prepare("PRAGMA incremental_vacuum")?;
+        let mut rows = statement.query([])?;
+        while rows.next()?.is_some() {}
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repository::{test_utils::*, Repository, SqliteRepository};
+
+    fn pragma(repository: &SqliteRepository, name: &str) -> i64 {
+        repository.get_connection().unwrap()
+            .query_row(&format!("PRAGMA {}", name), [], |row| row.get(0))
+            .unwrap()
+    }
+
+    fn insert_with_note(repository: &SqliteRepository) -> i64 {
+        let id = TestEncounter { cleared: false, ..Default::default() }.insert(repository);
+        let connection = repository.get_connection().unwrap();
+        repository.set_note(&connection, id, &"note ".repeat(4_000)).unwrap();
+        id
+    }
+
+    #[test]
+    fn should_skip_protected_encounters() {
+        let repository = setup();
+        let old = TestEncounter { fight_start: 1_000, cleared: false, ..Default::default() }.insert(&repository);
+        let favorite = TestEncounter { fight_start: 1_000, cleared: false, ..Default::default() }.insert(&repository);
+        let tagged = TestEncounter { fight_start: 1_000, cleared: false, ..Default::default() }.insert(&repository);
+        TestEncounter { fight_start: 1_000, cleared: true, ..Default::default() }.insert(&repository);
+        TestEncounter { fight_start: 5_000, cleared: false, ..Default::default() }.insert(&repository);
+
+        let connection = repository.get_connection().unwrap();
+        repository.set_favorite(&connection, favorite, true).unwrap();
+        repository.add_tags(&connection, tagged, &["prog".to_string()]).unwrap();
+
+        assert_eq!(repository.load_prunable_encounters(Some(2_000), None).unwrap(), vec![old]);
+        assert!(repository.load_prunable_encounters(None, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_keep_most_recent_per_boss() {
+        let repository = setup();
+        let narok = |fight_start| TestEncounter { fight_start, cleared: false, ..Default::default() };
+        let thaemine = |fight_start| TestEncounter { fight_start, cleared: false, boss: "Thaemine", ..Default::default() };
+
+        let oldest = narok(1_000).insert(&repository);
+        let older = narok(2_000).insert(&repository);
+        narok(3_000).insert(&repository);
+        narok(4_000).insert(&repository);
+        thaemine(1_000).insert(&repository);
+
+        assert_eq!(repository.load_prunable_encounters(None, Some(2)).unwrap(), vec![oldest, older]);
+    }
+
+    #[test]
+    fn should_convert_database_without_auto_vacuum() {
+        let repository = setup();
+        // what databases created before auto_vacuum was turned on look like
+        repository.get_connection().unwrap()
+            .execute_batch("PRAGMA auto_vacuum = NONE; VACUUM;")
+            .unwrap();
+
+        let ids: Vec<_> = (0..20).map(|_| insert_with_note(&repository)).collect();
+        let connection = repository.get_connection().unwrap();
+        repository.delete_encounters(&connection, &ids).unwrap();
+        drop(connection);
+
+        assert_eq!(pragma(&repository, "auto_vacuum"), 0);
+        assert!(pragma(&repository, "freelist_count") > 0);
+
+        repository.optimize_storage().unwrap();
+
+        assert_eq!(pragma(&repository, "auto_vacuum"), 2);
+        assert_eq!(pragma(&repository, "freelist_count"), 0);
+    }
+}
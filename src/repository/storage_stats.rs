@@ -0,0 +1,121 @@
+use rusqlite::Connection;
+
+use crate::{error::*, models::{ColumnSize, EncounterCost, StorageStats, TableSize}};
+
+use super::{queries::{SELECT_EVICTABLE_ENCOUNTERS, SELECT_TABLE_SIZES, SELECT_USED_BYTES}, SqliteRepository};
+
+/// Columns that hold the bulk of the data, compressed json blobs and json text.
+const MEASURED_COLUMNS: &[(&str, &str)] = &[
+    ("encounter", "buffs"),
+    ("encounter", "debuffs"),
+    ("encounter", "applied_shield_buffs"),
+    ("encounter", "boss_hp_log"),
+    ("encounter", "misc"),
+    ("encounter", "stagger_log"),
+    ("entity", "skills"),
+    ("entity", "damage_stats"),
+    ("entity", "skill_stats"),
+    ("entity", "engravings"),
+    ("entity", "ark_passive_data"),
+];
+
+impl SqliteRepository {
+
+    pub(crate) fn load_storage_stats_inner(&self) -> Result<StorageStats> {
+        let connection = self.pool.get()?;
+
+        let (used_bytes, free_bytes) = Self::load_page_sizes(&connection)?;
+
+        let mut statement = connection.prepare_cached(SELECT_TABLE_SIZES)?;
+        let tables = statement
+            .query_map([], |row| Ok(TableSize {
+                name: row.get(0)?,
+                bytes: row.get(1)?,
+            }))?
+            .collect::<Result<_, _>>()?;
+
+        let mut columns = vec![];
+
+        for (table, column) in MEASURED_COLUMNS {
+            let query = format!("SELECT IFNULL(SUM(LENGTH(CAST({} AS BLOB))), 0) FROM {}", column, table);
+            let bytes = connection.query_row(&query, [], |row| row.get(0))?;
+
+            columns.push(ColumnSize {
+                table: table.to_string(),
+                column: column.to_string(),
+                bytes,
+            });
+        }
+
+        Ok(StorageStats {
+            used_bytes,
+            free_bytes,
+            tables,
+            columns,
+        })
+    }
+
+    /// Oldest first, favorites, clears and tagged encounters are left out.
+    pub(crate) fn load_evictable_encounters_inner(&self) -> Result<Vec<EncounterCost>> {
+        let connection = self.pool.get()?;
+        let mut statement = connection.prepare_cached(SELECT_EVICTABLE_ENCOUNTERS)?;
+
+        let costs = statement
+            .query_map([], |row| Ok(EncounterCost {
+                encounter_id: row.get(0)?,
+                bytes: row.get(1)?,
+            }))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(costs)
+    }
+
+    pub(crate) fn load_used_bytes_inner(&self) -> Result<i64> {
+        let connection = self.pool.get()?;
+        let (used_bytes, _) = Self::load_page_sizes(&connection)?;
+
+        Ok(used_bytes)
+    }
+
+    fn load_page_sizes(connection: &Connection) -> Result<(i64, i64)> {
+        let sizes = connection.query_row(SELECT_USED_BYTES, [], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(sizes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repository::{test_utils::*, Repository};
+
+    #[test]
+    fn should_report_table_and_column_sizes() {
+        let repository = setup();
+        TestEncounter::default().insert(&repository);
+
+        let stats = repository.load_storage_stats().unwrap();
+
+        assert!(stats.used_bytes > 0);
+        assert_eq!(repository.load_used_bytes().unwrap(), stats.used_bytes);
+        assert!(stats.tables.iter().any(|table| table.name == "encounter" && table.bytes > 0));
+
+        let skills = stats.columns
+            .iter()
+            .find(|column| column.table == "entity" && column.column == "skills")
+            .unwrap();
+        assert!(skills.bytes > 0);
+    }
+
+    #[test]
+    fn should_list_evictable_encounters_oldest_first() {
+        let repository = setup();
+        let newer = TestEncounter { fight_start: 2_000, cleared: false, ..Default::default() }.insert(&repository);
+        let older = TestEncounter { fight_start: 1_000, cleared: false, ..Default::default() }.insert(&repository);
+        TestEncounter { fight_start: 500, cleared: true, ..Default::default() }.insert(&repository);
+
+        let costs = repository.load_evictable_encounters().unwrap();
+
+        let ids: Vec<_> = costs.iter().map(|cost| cost.encounter_id).collect();
+        assert_eq!(ids, vec![older, newer]);
+        assert!(costs.iter().all(|cost| cost.bytes > 0));
+    }
+}
@@ -0,0 +1,171 @@
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{error::*, models::SyncLog};
+
+use super::{queries::{RESET_FAILED_SYNC_LOGS, SELECT_FAILED_SYNCS, SELECT_SYNC_LOG, SELECT_UNSYNCED_ENCOUNTERS, UPSERT_SYNC_FAILURE, UPSERT_SYNC_SUCCESS}, SqliteRepository};
+
+impl SqliteRepository {
+
+    /// Oldest first, failed uploads are left out until they are retried.
+    pub(crate) fn load_unsynced_encounters_inner(&self, limit: i64) -> Result<Vec<i64>> {
+        self.load_ids(SELECT_UNSYNCED_ENCOUNTERS, limit)
+    }
+
+    pub(crate) fn load_failed_syncs_inner(&self, limit: i64) -> Result<Vec<i64>> {
+        self.load_ids(SELECT_FAILED_SYNCS, limit)
+    }
+
+    pub(crate) fn load_sync_log_inner(&self, encounter_id: i64) -> Result<Option<SyncLog>> {
+        let connection = self.pool.get()?;
+        let mut statement = connection.prepare_cached(SELECT_SYNC_LOG)?;
+
+        let sync_log = statement
+            .query_row(params![encounter_id], |row| Ok(SyncLog {
+                encounter_id: row.get(0)?,
+                upstream_id: row.get(1)?,
+                failed: row.get(2)?,
+            }))
+            .optional()?;
+
+        Ok(sync_log)
+    }
+
+    pub(crate) fn record_sync_success_inner(
+        &self,
+        connection: &Connection,
+        encounter_id: i64,
+        upstream_id: &str) -> Result<()> {
+        Self::ensure_encounter_exists(connection, encounter_id)?;
+
+        let mut statement = connection.prepare_cached(UPSERT_SYNC_SUCCESS)?;
+        statement.execute(params![encounter_id, upstream_id])?;
+
+        Ok(())
+    }
+
+    pub(crate) fn record_sync_failure_inner(
+        &self,
+        connection: &Connection,
+        encounter_id: i64) -> Result<()> {
+        Self::ensure_encounter_exists(connection, encounter_id)?;
+
+        let mut statement = connection.prepare_cached(UPSERT_SYNC_FAILURE)?;
+        statement.execute(params![encounter_id])?;
+
+        Ok(())
+    }
+
+    /// Clears the failed flag, the uploads that never reached upstream are listed as unsynced again.
+    /// The rows are kept so an upstream id recorded earlier isn't lost. Returns how many there were.
+    pub(crate) fn retry_failed_syncs_inner(&self, connection: &Connection) -> Result<usize> {
+        let mut statement = connection.prepare_cached(RESET_FAILED_SYNC_LOGS)?;
+        let retried = statement.execute([])?;
+
+        Ok(retried)
+    }
+
+    fn load_ids(&self, query: &str, limit: i64) -> Result<Vec<i64>> {
+        let connection = self.pool.get()?;
+        let mut statement = connection.prepare_cached(query)?;
+
+        let ids = statement
+            .query_map(params![limit], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::StoreError, models::SyncLog, repository::{test_utils::*, Repository}};
+
+    #[test]
+    fn should_track_uploads() {
+        let repository = setup();
+        let synced = TestEncounter::default().insert(&repository);
+        let failed = TestEncounter::default().insert(&repository);
+        let pending = TestEncounter::default().insert(&repository);
+        let connection = repository.get_connection().unwrap();
+
+        assert_eq!(repository.load_unsynced_encounters(10).unwrap(), vec![synced, failed, pending]);
+        assert_eq!(repository.load_unsynced_encounters(1).unwrap(), vec![synced]);
+
+        repository.record_sync_success(&connection, synced, "upstream").unwrap();
+        repository.record_sync_failure(&connection, failed).unwrap();
+
+        assert_eq!(repository.load_unsynced_encounters(10).unwrap(), vec![pending]);
+        assert_eq!(repository.load_failed_syncs(10).unwrap(), vec![failed]);
+        assert_eq!(repository.load_sync_log(synced).unwrap(), Some(SyncLog {
+            encounter_id: synced,
+            upstream_id: Some("upstream".into()),
+            failed: false,
+        }));
+        assert_eq!(repository.load_sync_log(pending).unwrap(), None);
+
+        assert_eq!(repository.retry_failed_syncs(&connection).unwrap(), 1);
+        assert_eq!(repository.load_unsynced_encounters(10).unwrap(), vec![failed, pending]);
+        assert!(repository.load_failed_syncs(10).unwrap().is_empty());
+        assert_eq!(repository.load_sync_log(failed).unwrap(), Some(SyncLog {
+            encounter_id: failed,
+            upstream_id: None,
+            failed: false,
+        }));
+    }
+
+    #[test]
+    fn should_overwrite_failure_with_success() {
+        let repository = setup();
+        let id = TestEncounter::default().insert(&repository);
+        let connection = repository.get_connection().unwrap();
+
+        repository.record_sync_failure(&connection, id).unwrap();
+        repository.record_sync_success(&connection, id, "upstream").unwrap();
+
+        assert!(repository.load_failed_syncs(10).unwrap().is_empty());
+        assert_eq!(repository.load_sync_log(id).unwrap().unwrap().upstream_id, Some("upstream".into()));
+    }
+
+    #[test]
+    fn should_keep_upstream_id_on_failure() {
+        let repository = setup();
+        let id = TestEncounter::default().insert(&repository);
+        let connection = repository.get_connection().unwrap();
+
+        repository.record_sync_success(&connection, id, "upstream").unwrap();
+        repository.record_sync_failure(&connection, id).unwrap();
+
+        assert_eq!(repository.load_sync_log(id).unwrap(), Some(SyncLog {
+            encounter_id: id,
+            upstream_id: Some("upstream".into()),
+            failed: true,
+        }));
+    }
+
+    #[test]
+    fn should_not_upload_again_after_retrying_synced_encounter() {
+        let repository = setup();
+        let id = TestEncounter::default().insert(&repository);
+        let connection = repository.get_connection().unwrap();
+
+        repository.record_sync_success(&connection, id, "upstream").unwrap();
+        repository.record_sync_failure(&connection, id).unwrap();
+
+        assert_eq!(repository.retry_failed_syncs(&connection).unwrap(), 1);
+        assert!(repository.load_unsynced_encounters(10).unwrap().is_empty());
+        assert_eq!(repository.load_sync_log(id).unwrap(), Some(SyncLog {
+            encounter_id: id,
+            upstream_id: Some("upstream".into()),
+            failed: false,
+        }));
+    }
+
+    #[test]
+    fn should_fail_to_record_missing_encounter() {
+        let repository = setup();
+        let connection = repository.get_connection().unwrap();
+
+        let result = repository.record_sync_success(&connection, 404, "upstream");
+        assert!(matches!(result, Err(StoreError::NotFound(404))));
+    }
+}
@@ -0,0 +1,170 @@
+use hashbrown::HashMap;
+use lost_metrics_core::models::{DamageStats, EntityType, SkillStats};
+use lost_metrics_misc::compress_json;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use serde_json::json;
+
+use crate::{connection_pool, migration_runner::MigrationRunner, models::*};
+
+use super::{Repository, SqliteRepository};
+
+pub fn setup() -> SqliteRepository {
+    let pool = connection_pool::in_memory().unwrap();
+    let repository = SqliteRepository::new(pool.clone());
+    let migration_runner = MigrationRunner::new(pool);
+
+    migration_runner.run().unwrap();
+
+    repository
+}
+
+/// Second repository over the same pool, for services that take ownership of theirs.
+pub fn same_database(repository: &SqliteRepository) -> SqliteRepository {
+    SqliteRepository::new(pool_of(repository))
+}
+
+pub fn pool_of(repository: &SqliteRepository) -> Pool<SqliteConnectionManager> {
+    repository.pool.clone()
+}
+
+pub struct TestPlayer {
+    pub name: &'static str,
+    pub class_id: u32,
+    pub character_id: u64,
+    pub gear_score: f32,
+    pub dps: i64,
+}
+
+pub struct TestEncounter {
+    pub fight_start: i64,
+    pub duration: i64,
+    pub boss: &'static str,
+    pub difficulty: &'static str,
+    pub local_player: &'static str,
+    pub players: Vec<TestPlayer>,
+    pub cleared: bool,
+    pub boss_only_damage: bool,
+    pub region: Option<&'static str>,
+}
+
+impl Default for TestEncounter {
+    fn default() -> Self {
+        Self {
+            fight_start: 1_000_000,
+            duration: 600_000,
+            boss: "Narok the Butcher",
+            difficulty: "Hard",
+            local_player: "test",
+            players: vec![TestPlayer {
+                name: "test",
+                class_id: 204,
+                character_id: 1,
+                gear_score: 1700.0,
+                dps: 100,
+            }],
+            cleared: true,
+            boss_only_damage: true,
+            region: None,
+        }
+    }
+}
+
+impl TestEncounter {
+    pub fn insert(&self, repository: &SqliteRepository) -> i64 {
+        let encounter = EncounterDb {
+            last_combat_packet: self.fight_start + self.duration,
+            total_damage_dealt: 0,
+            top_damage_dealt: 0,
+            total_damage_taken: 0,
+            top_damage_taken: 0,
+            dps: 0,
+            compressed_buffs: compress_json(&json!({})),
+            compressed_debuffs: compress_json(&json!({})),
+            total_shielding: 0,
+            total_effective_shielding: 0,
+            compressed_shields: compress_json(&json!({})),
+            misc_json: json!({ "raidClear": self.cleared, "region": self.region }),
+            db_version: 5,
+            compressed_boss_hp: compress_json(&json!({})),
+            stagger_stats_json: json!(null),
+        };
+
+        let local_player_dps = self.players
+            .iter()
+            .find(|player| player.name == self.local_player)
+            .map(|player| player.dps)
+            .unwrap_or_default();
+        let preview_players = self.players
+            .iter()
+            .map(|player| PreviewPlayer {
+                name: player.name.to_string(),
+                class_id: player.class_id,
+                spec: None,
+                gear_score: player.gear_score,
+                dps: player.dps,
+            })
+            .collect();
+
+        let encounter_preview = EncounterPreviewDb {
+            fight_start: self.fight_start,
+            current_boss_name: self.boss,
+            duration: self.duration,
+            players: preview_players,
+            raid_difficulty: self.difficulty,
+            local_player: self.local_player,
+            local_player_dps,
+            raid_clear: self.cleared.then_some(true),
+            boss_only_damage: self.boss_only_damage,
+        };
+
+        let skills = HashMap::new();
+        let entities: Vec<_> = self.players
+            .iter()
+            .map(|player| {
+                let damage_stats = DamageStats {
+                    dps: player.dps,
+                    damage_dealt: player.dps * self.duration / 1000,
+                    ..Default::default()
+                };
+
+                EntityDb {
+                    id: player.character_id,
+                    character_id: player.character_id,
+                    npc_id: 0,
+                    name: player.name,
+                    entity_type: EntityType::Player.to_string(),
+                    class_id: player.class_id,
+                    class: "",
+                    gear_score: player.gear_score,
+                    current_hp: 0,
+                    max_hp: 0,
+                    current_shield: 0,
+                    is_dead: false,
+                    skills: &skills,
+                    compressed_damage_stats: compress_json(&damage_stats),
+                    compressed_skills: compress_json(&skills),
+                    damage_stats,
+                    skill_stats: SkillStats::default(),
+                    engraving_data: None,
+                    gear_hash: None,
+                    ark_passive_active: None,
+                    ark_passive_data: None,
+                    spec: None,
+                    skill_stats_json: json!(SkillStats::default()),
+                    engraving_data_json: json!(null),
+                    ark_passive_data_json: json!(null),
+                }
+            })
+            .collect();
+
+        let mut connection = repository.get_connection().unwrap();
+        let transaction = connection.transaction().unwrap();
+        let encounter_id = repository.insert_encounter(&transaction, encounter).unwrap();
+        repository.insert_entities(&transaction, encounter_id, &entities).unwrap();
+        repository.insert_encounter_preview(&transaction, encounter_id, encounter_preview).unwrap();
+        transaction.commit().unwrap();
+
+        encounter_id
+    }
+}
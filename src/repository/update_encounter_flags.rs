@@ -0,0 +1,83 @@
+use rusqlite::{params, Connection};
+
+use crate::error::*;
+
+use super::{queries::{UPDATE_CLEARED, UPDATE_FAVORITE, UPDATE_RAID_CLEAR}, SqliteRepository};
+
+impl SqliteRepository {
+
+    pub(crate) fn set_favorite_inner(
+        &self,
+        connection: &Connection,
+        ids: &[i64],
+        favorite: bool) -> Result<()> {
+        Self::update_flag(connection, UPDATE_FAVORITE, ids, favorite)
+    }
+
+    pub(crate) fn set_cleared_inner(
+        &self,
+        connection: &Connection,
+        ids: &[i64],
+        cleared: bool) -> Result<()> {
+        Self::update_flag(connection, UPDATE_CLEARED, ids, cleared)?;
+        Self::update_flag(connection, UPDATE_RAID_CLEAR, ids, cleared)
+    }
+
+    /// Fails on the first unknown id, the caller is expected to roll back whatever was updated before it.
+    fn update_flag(
+        connection: &Connection,
+        query: &str,
+        ids: &[i64],
+        value: bool) -> Result<()> {
+
+        let mut statement = connection.prepare_cached(query)?;
+
+        for id in ids {
+            if statement.execute(params![id, value])? == 0 {
+                return Err(StoreError::NotFound(*id));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::StoreError, repository::{test_utils::*, Repository}};
+
+    fn flags(repository: &impl Repository, id: i64) -> (bool, bool) {
+        repository
+            .get_connection()
+            .unwrap()
+            .query_row("SELECT favorite, cleared FROM encounter_preview WHERE id = ?1", [id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+    }
+
+    #[test]
+    fn should_update_favorite_and_cleared() {
+        let repository = setup();
+        let first = TestEncounter::default().insert(&repository);
+        let second = TestEncounter::default().insert(&repository);
+        let connection = repository.get_connection().unwrap();
+
+        repository.set_favorite_many(&connection, &[first, second], true).unwrap();
+        repository.set_cleared(&connection, second, false).unwrap();
+
+        assert_eq!(flags(&repository, first), (true, true));
+        assert_eq!(flags(&repository, second), (true, false));
+    }
+
+    #[test]
+    fn should_fail_on_unknown_id() {
+        let repository = setup();
+        let id = TestEncounter::default().insert(&repository);
+        let connection = repository.get_connection().unwrap();
+
+        let result = repository.set_favorite_many(&connection, &[id, 404], true);
+        assert!(matches!(result, Err(StoreError::NotFound(404))));
+
+        let result = repository.set_cleared(&connection, 404, true);
+        assert!(matches!(result, Err(StoreError::NotFound(404))));
+    }
+}
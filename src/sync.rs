@@ -0,0 +1,449 @@
+use std::{fmt, thread, time::Duration};
+
+use log::*;
+
+#[cfg(test)]
+use mockall::automock;
+
+use crate::{error::*, repository::Repository};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UploadError {
+    /// Upstream asked to slow down, optionally saying for how long.
+    RateLimited(Option<Duration>),
+    /// Network errors and server side failures, worth retrying.
+    Transient(String),
+    /// Upstream refused the encounter, retrying won't change that.
+    Rejected(String),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::RateLimited(_) => write!(f, "rate limited"),
+            UploadError::Transient(message) => write!(f, "upload failed: {}", message),
+            UploadError::Rejected(message) => write!(f, "upload rejected: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+/// Transport to the upstream service, the driver takes care of retries and bookkeeping.
+#[cfg_attr(test, automock)]
+pub trait SyncClient : Send + Sync + 'static {
+    /// Uploads the json serialized encounter and returns the id upstream gave it.
+    fn upload(&self, encounter_id: i64, payload: &[u8]) -> Result<String, UploadError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    /// Number of encounters read from the database at a time.
+    pub batch_size: i64,
+    /// Tries per encounter before it is recorded as failed.
+    pub max_attempts: u32,
+    /// Doubles after every failed try, up to `max_backoff`.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 50,
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SyncReport {
+    pub uploaded: usize,
+    pub failed: usize,
+}
+
+/// Uploads every encounter that is not in `sync_logs` yet and records the outcome there.
+pub struct SyncDriver<R: Repository, C: SyncClient> {
+    repository: R,
+    client: C,
+    options: SyncOptions,
+}
+
+impl<R: Repository, C: SyncClient> SyncDriver<R, C> {
+    pub fn new(repository: R, client: C, options: SyncOptions) -> Self {
+        Self {
+            repository,
+            client,
+            options,
+        }
+    }
+
+    /// Runs until no unsynced encounters are left, failed uploads are not tried again.
+    pub fn run(&self) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+
+        loop {
+            let ids = self.repository.load_unsynced_encounters(self.options.batch_size)?;
+
+            if ids.is_empty() {
+                break;
+            }
+
+            for id in ids {
+                self.sync_encounter(id, &mut report)?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Marks the failed uploads as unsynced and runs again.
+    pub fn retry_failed(&self) -> Result<SyncReport> {
+        let connection = self.repository.get_connection()?;
+        self.repository.retry_failed_syncs(&connection)?;
+
+        self.run()
+    }
+
+    fn sync_encounter(&self, id: i64, report: &mut SyncReport) -> Result<()> {
+        let payload = match self.repository.load_encounter(id) {
+            Ok(encounter) => serde_json::to_vec(&encounter)?,
+            // an encounter without a preview can't be loaded either, skipping it keeps it from blocking every run
+            Err(err @ (StoreError::Corrupt(_) | StoreError::NotFound(_))) => {
+                warn!("could not read encounter {} for upload: {}", id, err);
+                let connection = self.repository.get_connection()?;
+                self.repository.record_sync_failure(&connection, id)?;
+                report.failed += 1;
+                return Ok(());
+            },
+            Err(err) => return Err(err),
+        };
+
+        // taken after the upload, holding it through the backoff would starve a small pool
+        let result = self.upload_with_retry(id, &payload);
+        let connection = self.repository.get_connection()?;
+
+        match result {
+            Ok(upstream_id) => {
+                self.repository.record_sync_success(&connection, id, &upstream_id)?;
+                report.uploaded += 1;
+            },
+            Err(err) => {
+                warn!("could not upload encounter {}: {}", id, err);
+                self.repository.record_sync_failure(&connection, id)?;
+                report.failed += 1;
+            },
+        }
+
+        Ok(())
+    }
+
+    fn upload_with_retry(&self, id: i64, payload: &[u8]) -> Result<String, UploadError> {
+        let mut backoff = self.options.initial_backoff;
+        let mut attempt = 1;
+
+        loop {
+            let delay = match self.client.upload(id, payload) {
+                Ok(upstream_id) => return Ok(upstream_id),
+                Err(err @ UploadError::Rejected(_)) => return Err(err),
+                Err(err) if attempt >= self.options.max_attempts => return Err(err),
+                Err(UploadError::RateLimited(Some(retry_after))) => retry_after,
+                Err(_) => backoff,
+            };
+
+            debug!("retrying upload of encounter {} in {:?}", id, delay);
+            thread::sleep(delay);
+
+            backoff = (backoff * 2).min(self.options.max_backoff);
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use crate::{connection_pool::PoolBuilder, migration_runner::MigrationRunner, repository::{test_utils::*, SqliteRepository}};
+
+    use super::*;
+
+    /// Stand-in for the upstream service.
+    ///
+    /// Accepts at most one upload per `min_interval` and answers 429 to the rest.
+    /// Encounters in `failures` get that many 500s before they go through, ones in `rejected` always get a 400.
+    struct TestServer {
+        address: SocketAddr,
+        state: Arc<Mutex<ServerState>>,
+    }
+
+    #[derive(Default)]
+    struct ServerState {
+        min_interval: Duration,
+        last_accepted: Option<Instant>,
+        failures: HashMap<i64, u32>,
+        rejected: Vec<i64>,
+        requests: usize,
+        uploaded: Vec<i64>,
+    }
+
+    impl TestServer {
+        fn start(state: ServerState) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let state = Arc::new(Mutex::new(state));
+            let server_state = state.clone();
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else { break };
+                    Self::handle(stream, &server_state);
+                }
+            });
+
+            Self { address, state }
+        }
+
+        fn handle(stream: TcpStream, state: &Mutex<ServerState>) {
+            let mut reader = BufReader::new(&stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+
+                if header.trim().is_empty() {
+                    break;
+                }
+
+                if let Some(value) = header.to_ascii_lowercase().strip_prefix("content-length:") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            let id: i64 = request_line
+                .split_whitespace()
+                .nth(1)
+                .and_then(|path| path.strip_prefix("/encounters/"))
+                .and_then(|id| id.parse().ok())
+                .unwrap();
+
+            let (status, body) = {
+                let mut state = state.lock().unwrap();
+                state.requests += 1;
+
+                let now = Instant::now();
+                let rate_limited = state
+                    .last_accepted
+                    .is_some_and(|last_accepted| now - last_accepted < state.min_interval);
+
+                if rate_limited {
+                    ("429 Too Many Requests", String::new())
+                } else {
+                    state.last_accepted = Some(now);
+
+                    if state.rejected.contains(&id) {
+                        ("400 Bad Request", "invalid encounter".to_string())
+                    } else if let Some(failures) = state.failures.get_mut(&id).filter(|failures| **failures > 0) {
+                        *failures -= 1;
+                        ("500 Internal Server Error", String::new())
+                    } else {
+                        state.uploaded.push(id);
+                        ("200 OK", format!("upstream-{}", id))
+                    }
+                }
+            };
+
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body);
+            (&stream).write_all(response.as_bytes()).unwrap();
+        }
+    }
+
+    struct HttpSyncClient {
+        address: SocketAddr,
+    }
+
+    impl SyncClient for HttpSyncClient {
+        fn upload(&self, encounter_id: i64, payload: &[u8]) -> Result<String, UploadError> {
+            let transient = |err: std::io::Error| UploadError::Transient(err.to_string());
+
+            let mut stream = TcpStream::connect(self.address).map_err(transient)?;
+            let request = format!(
+                "POST /encounters/{} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                encounter_id,
+                self.address,
+                payload.len());
+            stream.write_all(request.as_bytes()).map_err(transient)?;
+            stream.write_all(payload).map_err(transient)?;
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).map_err(transient)?;
+
+            let status: u16 = response
+                .split_whitespace()
+                .nth(1)
+                .and_then(|status| status.parse().ok())
+                .unwrap_or_default();
+            let body = response.split("\r\n\r\n").nth(1).unwrap_or_default().to_string();
+
+            match status {
+                200..=299 => Ok(body),
+                429 => Err(UploadError::RateLimited(None)),
+                400..=499 => Err(UploadError::Rejected(body)),
+                _ => Err(UploadError::Transient(format!("status {}", status))),
+            }
+        }
+    }
+
+    fn options() -> SyncOptions {
+        SyncOptions {
+            batch_size: 2,
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(5),
+            max_backoff: Duration::from_millis(40),
+        }
+    }
+
+    fn driver(repository: SqliteRepository, server: &TestServer, options: SyncOptions) -> SyncDriver<SqliteRepository, HttpSyncClient> {
+        SyncDriver::new(repository, HttpSyncClient { address: server.address }, options)
+    }
+
+    #[test]
+    fn should_upload_through_rate_limit_and_transient_errors() {
+        let repository = setup();
+        let ids: Vec<_> = (0..5).map(|_| TestEncounter::default().insert(&repository)).collect();
+
+        let server = TestServer::start(ServerState {
+            min_interval: Duration::from_millis(20),
+            failures: HashMap::from([(ids[1], 2)]),
+            ..Default::default()
+        });
+
+        let connection = repository.get_connection().unwrap();
+        let driver = driver(same_database(&repository), &server, options());
+        let report = driver.run().unwrap();
+
+        assert_eq!(report, SyncReport { uploaded: 5, failed: 0 });
+
+        let state = server.state.lock().unwrap();
+        assert_eq!(state.uploaded, ids);
+        assert!(state.requests > 5, "expected the rate limit and failures to cause retries");
+
+        let upstream_id: String = connection
+            .query_row("SELECT upstream_id FROM sync_logs WHERE encounter_id = ?1", [ids[1]], |row| row.get(0))
+            .unwrap();
+        assert_eq!(upstream_id, format!("upstream-{}", ids[1]));
+    }
+
+    #[test]
+    fn should_record_failures_and_retry_them() {
+        let repository = setup();
+        let rejected = TestEncounter::default().insert(&repository);
+        let flaky = TestEncounter::default().insert(&repository);
+
+        let server = TestServer::start(ServerState {
+            rejected: vec![rejected],
+            failures: HashMap::from([(flaky, 3)]),
+            ..Default::default()
+        });
+
+        let options = SyncOptions {
+            max_attempts: 2,
+            ..options()
+        };
+        let driver = driver(same_database(&repository), &server, options);
+
+        assert_eq!(driver.run().unwrap(), SyncReport { uploaded: 0, failed: 2 });
+        assert_eq!(repository.load_failed_syncs(10).unwrap(), vec![rejected, flaky]);
+        assert_eq!(driver.run().unwrap(), SyncReport::default());
+
+        server.state.lock().unwrap().rejected.clear();
+
+        assert_eq!(driver.retry_failed().unwrap(), SyncReport { uploaded: 2, failed: 0 });
+        assert!(repository.load_failed_syncs(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_not_retry_rejected_upload() {
+        let repository = setup();
+        let id = TestEncounter::default().insert(&repository);
+
+        let mut client = MockSyncClient::new();
+        client
+            .expect_upload()
+            .times(1)
+            .returning(|_, _| Err(UploadError::Rejected("invalid encounter".into())));
+
+        let driver = SyncDriver::new(same_database(&repository), client, options());
+
+        assert_eq!(driver.run().unwrap(), SyncReport { uploaded: 0, failed: 1 });
+        assert_eq!(repository.load_failed_syncs(10).unwrap(), vec![id]);
+    }
+
+    #[test]
+    fn should_record_unreadable_encounter_and_continue() {
+        let repository = setup();
+        let without_preview = TestEncounter::default().insert(&repository);
+        TestEncounter::default().insert(&repository);
+
+        repository
+            .get_connection()
+            .unwrap()
+            .execute("DELETE FROM encounter_preview WHERE id = ?1", [without_preview])
+            .unwrap();
+
+        let mut client = MockSyncClient::new();
+        client
+            .expect_upload()
+            .times(1)
+            .returning(|id, _| Ok(format!("upstream-{}", id)));
+
+        let driver = SyncDriver::new(same_database(&repository), client, options());
+
+        assert_eq!(driver.run().unwrap(), SyncReport { uploaded: 1, failed: 1 });
+        assert_eq!(repository.load_failed_syncs(10).unwrap(), vec![without_preview]);
+        assert_eq!(driver.run().unwrap(), SyncReport::default());
+    }
+
+    #[test]
+    fn should_not_hold_connection_while_uploading() {
+        let pool = PoolBuilder::in_memory()
+            .max_size(1)
+            .connection_timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        MigrationRunner::new(pool.clone()).run().unwrap();
+        let repository = SqliteRepository::new(pool.clone());
+        TestEncounter::default().insert(&repository);
+
+        let mut client = MockSyncClient::new();
+        client
+            .expect_upload()
+            .times(1)
+            .returning(move |_, _| match pool.try_get() {
+                Some(_) => Ok("upstream".into()),
+                None => Err(UploadError::Rejected("pool exhausted".into())),
+            });
+
+        let driver = SyncDriver::new(repository, client, options());
+
+        assert_eq!(driver.run().unwrap(), SyncReport { uploaded: 1, failed: 0 });
+    }
+}
@@ -1,11 +1,32 @@
-use std::collections::BTreeMap;
+use std::{cmp::Reverse, collections::BTreeMap, io::Read};
 
+use flate2::read::GzDecoder;
 use hashbrown::HashMap;
 use lost_metrics_core::models::*;
 use lost_metrics_misc::*;
+use rusqlite::{types::{FromSqlError, Type, ValueRef}, Connection, Row};
+use serde::de::DeserializeOwned;
 use serde_json::json;
 
-use crate::models::EntityDb;
+use crate::{error::Result, models::{EntityDb, PreviewPlayer}};
+
+/// The columns [`EncounterEntity::is_active_player`] and [`to_preview_players`] look at, the heavy blobs are skipped.
+const SELECT_PREVIEW_ENTITIES: &str = r"
+SELECT
+    name,
+    character_id,
+    npc_id,
+    entity_type,
+    class_id,
+    class,
+    gear_score,
+    current_hp,
+    max_hp,
+    is_dead,
+    spec,
+    damage_stats
+FROM entity
+WHERE encounter_id = ?1";
 
 
 pub fn to_entities_db<'a>(
@@ -67,36 +88,7 @@ pub fn to_entities_db<'a>(
             entity.skill_stats.identity_stats = stats;
         }
 
-        let compressed_skills = compress_json(skills);
-        let compressed_damage_stats = compress_json(&entity.damage_stats);
-
-        let entity_db = EntityDb {
-            id: entity.id,
-            compressed_damage_stats,
-            compressed_skills,
-            character_id: entity.character_id,
-            npc_id: entity.npc_id,
-            name: &entity.name,
-            entity_type: entity.entity_type.to_string(),
-            class_id: entity.class_id,
-            class: &entity.class,
-            gear_score: entity.gear_score,
-            current_hp: entity.current_hp,
-            max_hp: entity.max_hp,
-            current_shield: entity.current_shield,
-            is_dead: entity.is_dead,
-            skills: &entity.skills,
-            damage_stats: entity.damage_stats.clone(),
-            skill_stats: entity.skill_stats.clone(),
-            engraving_data: entity.engraving_data.clone(),
-            gear_hash: entity.gear_hash.clone(),
-            ark_passive_active: entity.ark_passive_active,
-            ark_passive_data: entity.ark_passive_data.clone(),
-            spec: entity.spec.as_ref(),
-            skill_stats_json: json!(entity.skill_stats),
-            engraving_data_json: json!(entity.engraving_data),
-            ark_passive_data_json: json!(entity.ark_passive_data)
-        };
+        let entity_db = to_entity_db(entity);
 
         entities.push(entity_db);
     }
@@ -104,3 +96,133 @@ pub fn to_entities_db<'a>(
     entities
 }
 
+pub fn to_entity_db(entity: &EncounterEntity) -> EntityDb<'_> {
+    EntityDb {
+        id: entity.id,
+        compressed_damage_stats: compress_json(&entity.damage_stats),
+        compressed_skills: compress_json(&entity.skills),
+        character_id: entity.character_id,
+        npc_id: entity.npc_id,
+        name: &entity.name,
+        entity_type: entity.entity_type.to_string(),
+        class_id: entity.class_id,
+        class: &entity.class,
+        gear_score: entity.gear_score,
+        current_hp: entity.current_hp,
+        max_hp: entity.max_hp,
+        current_shield: entity.current_shield,
+        is_dead: entity.is_dead,
+        skills: &entity.skills,
+        damage_stats: entity.damage_stats.clone(),
+        skill_stats: entity.skill_stats.clone(),
+        engraving_data: entity.engraving_data.clone(),
+        gear_hash: entity.gear_hash.clone(),
+        ark_passive_active: entity.ark_passive_active,
+        ark_passive_data: entity.ark_passive_data.clone(),
+        spec: entity.spec.as_ref(),
+        skill_stats_json: json!(entity.skill_stats),
+        engraving_data_json: json!(entity.engraving_data),
+        ark_passive_data_json: json!(entity.ark_passive_data)
+    }
+}
+
+/// Players shown on the preview, highest damage first. Callers pick them with [`EncounterEntity::is_active_player`].
+pub fn to_preview_players(mut players: Vec<&EncounterEntity>) -> Vec<PreviewPlayer> {
+    players.sort_unstable_by(|a, b| Reverse(a.damage_stats.damage_dealt)
+        .cmp(&Reverse(b.damage_stats.damage_dealt))
+        .then_with(|| a.name.cmp(&b.name)));
+    players
+        .into_iter()
+        .map(|e| PreviewPlayer {
+            name: e.name.clone(),
+            class_id: e.class_id,
+            spec: e.spec.clone(),
+            gear_score: e.gear_score,
+            dps: e.damage_stats.dps,
+        })
+        .collect()
+}
+
+/// `class_id:name` pairs for `encounter_preview.players`, kept for the full text search and for older builds that still parse it.
+pub fn to_players_column(players: &[PreviewPlayer]) -> String {
+    players
+        .iter()
+        .map(|player| format!("{}:{}", player.class_id, player.name))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Same players [`to_preview_players`] gave when the encounter was saved, read back from `entity`.
+pub fn load_preview_players(connection: &Connection, encounter_id: i64, local_player: &str) -> rusqlite::Result<Vec<PreviewPlayer>> {
+    let mut statement = connection.prepare_cached(SELECT_PREVIEW_ENTITIES)?;
+
+    let entities = statement
+        .query_map([encounter_id], |row| {
+            let entity_type: String = row.get(3)?;
+
+            Ok(EncounterEntity {
+                name: row.get(0)?,
+                character_id: row.get::<_, Option<u64>>(1)?.unwrap_or_default(),
+                npc_id: row.get::<_, Option<u32>>(2)?.unwrap_or_default(),
+                entity_type: entity_type.parse().unwrap_or_default(),
+                class_id: row.get::<_, Option<u32>>(4)?.unwrap_or_default(),
+                class: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+                gear_score: row.get::<_, Option<f32>>(6)?.unwrap_or_default(),
+                current_hp: row.get::<_, Option<i64>>(7)?.unwrap_or_default(),
+                max_hp: row.get::<_, Option<i64>>(8)?.unwrap_or_default(),
+                is_dead: row.get::<_, Option<bool>>(9)?.unwrap_or_default(),
+                spec: row.get(10)?,
+                damage_stats: get_compressed_json(row, 11)?,
+                ..Default::default()
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let players = entities
+        .iter()
+        .filter(|entity| entity.is_active_player(local_player))
+        .collect();
+
+    Ok(to_preview_players(players))
+}
+
+/// Reverses [`compress_json`].
+pub fn decompress_json<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    let mut decoder = GzDecoder::new(bytes);
+    let mut json = vec![];
+    decoder.read_to_end(&mut json)?;
+    let value = serde_json::from_slice(&json)?;
+
+    Ok(value)
+}
+
+/// Reads a column written with [`compress_json`].
+///
+/// Older databases stored the same columns as plain json text, so text values are parsed as is.
+/// Empty and `NULL` values yield the default.
+pub fn get_compressed_json<T: DeserializeOwned + Default>(row: &Row, index: usize) -> rusqlite::Result<T> {
+    let value = match row.get_ref(index)? {
+        ValueRef::Null => return Ok(T::default()),
+        ValueRef::Blob([]) => return Ok(T::default()),
+        ValueRef::Blob(bytes) => decompress_json(bytes)
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(index, Type::Blob, err.into()))?,
+        ValueRef::Text(text) => serde_json::from_slice(text)
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, err.into()))?,
+        value => return Err(rusqlite::Error::FromSqlConversionFailure(
+            index, value.data_type(), FromSqlError::InvalidType.into())),
+    };
+
+    Ok(value)
+}
+
+/// Reads a json text column, `NULL` yields the default.
+pub fn get_json<T: DeserializeOwned + Default>(row: &Row, index: usize) -> rusqlite::Result<T> {
+    let value: serde_json::Value = row.get(index)?;
+
+    if value.is_null() {
+        return Ok(T::default());
+    }
+
+    serde_json::from_value(value)
+        .map_err(|err| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, err.into()))
+}